@@ -0,0 +1,46 @@
+//! Compares freeing through the mpsc channel with `new_single_threaded`'s local queue.
+//! Run with `cargo bench --bench reclaim`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use buddy_system::arena::BuddyBookkeeping;
+
+const ROUNDS: usize = 20_000;
+const BATCH: usize = 64;
+
+/// Allocates a batch, drops it, and tidies, `ROUNDS` times over. Returns the total time, and
+/// the time spent just dropping.
+fn churn(mut bookkeeping: BuddyBookkeeping) -> (Duration, Duration) {
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut dropping = Duration::ZERO;
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        batch.extend((0..BATCH).map(|_| bookkeeping.alloc(8).unwrap()));
+        black_box(&batch);
+
+        let drop_start = Instant::now();
+        batch.clear();
+        dropping += drop_start.elapsed();
+
+        bookkeeping.tidy();
+    }
+
+    (start.elapsed(), dropping)
+}
+
+fn main() {
+    let channel = churn(BuddyBookkeeping::new(1 << 16, 8, 1 << 16));
+    let local = churn(BuddyBookkeeping::new_single_threaded(1 << 16, 8, 1 << 16));
+
+    let per_free = |total: Duration| total / (ROUNDS * BATCH) as u32;
+    for (name, (total, dropping)) in [("channel", channel), ("local", local)] {
+        println!(
+            "{name:8} {total:?} in all, {:?} per drop",
+            per_free(dropping)
+        );
+    }
+}
@@ -1,192 +1,5901 @@
-use std::{iter::repeat_with, ops::Range, sync::mpsc, time::Instant};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet, TryReserveError, VecDeque},
+    iter::repeat_with,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{ControlFlow, Deref, Range},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use generational_arena::{Arena, Index};
 
-use crate::{
-    buddy::{self, is_pow_of_two, Block, BlockState},
-    pretty_print::prettify,
-};
+use crate::buddy::{self, is_pow_of_two, Bias, Block, BlockState, Pow2};
 
 /// NOT Copy or Clone, to make sure it's unique
 #[derive(Debug)]
 pub struct Allocation {
     index: Index,
     range: Range<usize>,
-    to_remove: mpsc::Sender<Index>,
+    /// Tells this allocation apart from others that later reuse the same block
+    serial: u64,
+    /// `None` once something else has taken over freeing the block
+    to_remove: Option<Reclaim>,
+    /// Shared between the pieces of a split allocation, so only the last one frees the block
+    shared: Option<Arc<()>>,
+    /// Pinned allocations are never moved to another place
+    pinned: bool,
+    /// Added to `range` when it's handed out, see `BuddyBookkeeping::set_base_offset`
+    base: usize,
+    /// How much of `range` is data rather than padding, from `BuddyArena::alloc_padded`
+    logical_len: Option<usize>,
 }
 
-impl Allocation {
-    pub fn range(&self) -> Range<usize> {
-        self.range.clone()
+/// Where a dropped allocation sends its index to be freed on the next tidy
+#[derive(Debug, Clone)]
+enum Reclaim {
+    Channel(mpsc::Sender<Index>),
+    /// For bookkeepings made by a `ReclaimHub`, tagged with which one it was
+    Tagged(mpsc::Sender<(usize, Index)>, usize),
+    /// For bookkeepings made with `new_single_threaded`, the id of their queue in
+    /// `LOCAL_QUEUES`. Only the id is held, so `Allocation` stays `Send`.
+    Local(usize),
+}
+
+thread_local! {
+    /// The free queues of the `new_single_threaded` bookkeepings made on this thread, by id
+    static LOCAL_QUEUES: RefCell<HashMap<usize, Vec<Index>>> = RefCell::new(HashMap::new());
+}
+
+static NEXT_LOCAL_QUEUE: AtomicUsize = AtomicUsize::new(0);
+
+impl Reclaim {
+    fn send(&self, index: Index) {
+        match self {
+            // don't panic here; leaking is not considered unsafe if the
+            // receiver doesn't get it for whatever reason
+            Reclaim::Channel(sender) => {
+                let _ = sender.send(index);
+            }
+            Reclaim::Tagged(sender, arena) => {
+                let _ = sender.send((*arena, index));
+            }
+            // on another thread (or while the thread is exiting), there's no queue to push to
+            Reclaim::Local(id) => {
+                let _ = LOCAL_QUEUES.try_with(|queues| {
+                    if let Some(queue) = queues.borrow_mut().get_mut(id) {
+                        queue.push(index);
+                    }
+                });
+            }
+        }
     }
 }
 
-impl Drop for Allocation {
-    fn drop(&mut self) {
-        // don't panic here; leaking is not considered unsafe if the
-        // receiver doesn't get it for whatever reason
-        let _ = self.to_remove.send(self.index);
+/// The receiving end of `Reclaim`
+enum ReclaimQueue {
+    Channel(mpsc::Receiver<Index>),
+    /// Frees go to a `ReclaimHub` instead, which hands them over on its own tidy
+    Hub,
+    Local(usize),
+}
+
+impl ReclaimQueue {
+    fn try_recv(&self) -> Option<Index> {
+        match self {
+            ReclaimQueue::Channel(receiver) => receiver.try_recv().ok(),
+            ReclaimQueue::Hub => None,
+            ReclaimQueue::Local(id) => LOCAL_QUEUES
+                .try_with(|queues| queues.borrow_mut().get_mut(id)?.pop())
+                .ok()
+                .flatten(),
+        }
     }
 }
 
-pub struct BuddyBookkeeping {
-    pub(crate) blocks: Arena<Block>,
-    pub(crate) root: Index,
-    to_remove_sender: mpsc::Sender<Index>,
-    to_remove_receiver: mpsc::Receiver<Index>,
-    min_block_size: usize,
-    max_block_size: usize,
+impl Drop for ReclaimQueue {
+    fn drop(&mut self) {
+        if let ReclaimQueue::Local(id) = self {
+            let _ = LOCAL_QUEUES.try_with(|queues| queues.borrow_mut().remove(id));
+        }
+    }
 }
 
-impl BuddyBookkeeping {
-    pub fn new(size: usize, min_block_size: usize, max_block_size: usize) -> BuddyBookkeeping {
-        assert!(is_pow_of_two(size));
-        assert!(max_block_size <= size);
-        assert!(min_block_size <= max_block_size);
+/// One queue of frees for a whole pool of bookkeepings, so a single cleanup loop can tidy all
+/// of them. Each free is tagged with the bookkeeping it belongs to.
+pub struct ReclaimHub {
+    sender: mpsc::Sender<(usize, Index)>,
+    receiver: mpsc::Receiver<(usize, Index)>,
+    next_id: usize,
+}
 
-        let mut new_arena = Arena::new();
-        let root = new_arena.insert(Block {
-            range: 0..size,
-            state: BlockState::Available,
-        });
+impl Default for ReclaimHub {
+    fn default() -> ReclaimHub {
+        ReclaimHub::new()
+    }
+}
 
+impl ReclaimHub {
+    pub fn new() -> ReclaimHub {
         let (sender, receiver) = mpsc::channel();
 
-        BuddyBookkeeping {
-            to_remove_sender: sender,
-            to_remove_receiver: receiver,
-            blocks: new_arena,
-            root: root,
+        ReclaimHub {
+            sender,
+            receiver,
+            next_id: 0,
+        }
+    }
+
+    /// Makes a bookkeeping like `BuddyBookkeeping::new`, but whose frees are sent here. Its
+    /// own tidies won't see them until they've been handed over by `ReclaimHub::tidy`.
+    pub fn bookkeeping(
+        &mut self,
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> BuddyBookkeeping {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        BuddyBookkeeping::with_queue(
+            size,
             min_block_size,
             max_block_size,
+            Reclaim::Tagged(self.sender.clone(), id),
+            ReclaimQueue::Hub,
+        )
+    }
+
+    /// Hands every free so far to the bookkeeping it came from, and tidies them all. Frees for
+    /// bookkeepings that aren't in `bookkeepings` are dropped, leaking their blocks.
+    pub fn tidy(&self, bookkeepings: &mut [&mut BuddyBookkeeping]) {
+        for (arena, index) in self.receiver.try_iter() {
+            let owner = bookkeepings
+                .iter_mut()
+                .find(|bookkeeping| bookkeeping.arena_id() == Some(arena));
+
+            if let Some(owner) = owner {
+                owner.received.get_mut().push(index);
+            }
+        }
+
+        for bookkeeping in bookkeepings {
+            bookkeeping.tidy();
         }
     }
+}
 
-    pub fn alloc(&mut self, count: usize) -> Option<Allocation> {
-        let best_size = if 2_u32.pow(count.ilog2()) as usize == count {
-            count
-        } else {
-            2_u32.pow(count.ilog2() + 1) as usize
+/// Whether the tree under `root` has an available block of at least `size`
+fn has_free_block(blocks: &Arena<Block>, root: Index, size: usize) -> bool {
+    let mut stack = vec![root];
+
+    while let Some(index) = stack.pop() {
+        let block = &blocks[index];
+
+        match block.state {
+            BlockState::Available if block.range.len() >= size => return true,
+            BlockState::Split(first, second) if block.range.len() > size => {
+                stack.push(first);
+                stack.push(second);
+            }
+            _ => {}
         }
-        .max(self.min_block_size)
-        .min(self.max_block_size);
+    }
 
-        if best_size < count {
-            return None;
+    false
+}
+
+/// Converts `x` elements of `from` bytes each into elements of `to` bytes, if it divides evenly
+fn rescale(x: usize, from: usize, to: usize) -> Option<usize> {
+    let bytes = x.checked_mul(from)?;
+
+    (bytes % to == 0).then_some(bytes / to)
+}
+
+impl Allocation {
+    pub fn range(&self) -> Range<usize> {
+        (self.base + self.range.start)..(self.base + self.range.end)
+    }
+
+    /// Converts the range (along with the base offset and logical length) to match an arena
+    /// that went through `BuddyArena::transmute_elements` from `T` to `U`. Hands the
+    /// allocation back unchanged if any of them doesn't divide evenly.
+    pub fn transmute_elements<T, U>(mut self) -> Result<Allocation, Allocation> {
+        let (from, to) = (std::mem::size_of::<T>(), std::mem::size_of::<U>());
+
+        let logical_len = match self.logical_len {
+            Some(len) => rescale(len, from, to).map(Some),
+            None => Some(None),
+        };
+
+        match (
+            rescale(self.range.start, from, to),
+            rescale(self.range.end, from, to),
+            rescale(self.base, from, to),
+            logical_len,
+        ) {
+            (Some(start), Some(end), Some(base), Some(logical_len)) => {
+                self.range = start..end;
+                self.base = base;
+                self.logical_len = logical_len;
+
+                Ok(self)
+            }
+            _ => Err(self),
         }
+    }
 
-        buddy::alloc(&mut self.blocks, self.root, best_size).map(|x| Allocation {
-            index: x,
-            range: (self.blocks[x].range.start)..(self.blocks[x].range.start + count),
-            to_remove: self.to_remove_sender.clone(),
-        })
+    /// Stops this handle from freeing the block when it's dropped. Returns the block's index
+    /// if this was its last owner, in which case freeing it is up to the caller.
+    pub(crate) fn disown(mut self) -> Option<Index> {
+        self.to_remove = None;
+
+        let last = self
+            .shared
+            .take()
+            .is_none_or(|shared| Arc::into_inner(shared).is_some());
+
+        last.then_some(self.index)
     }
 
-    pub fn tidy(&mut self) {
-        while let Ok(index) = self.to_remove_receiver.try_recv() {
-            buddy::dealloc(&mut self.blocks, index);
+    /// A copy of this handle that never frees the block, like a range copied out and held
+    /// on to after the original was reallocated or dropped
+    #[cfg(test)]
+    pub(crate) fn detached_copy(&self) -> Allocation {
+        Allocation {
+            index: self.index,
+            range: self.range.clone(),
+            serial: self.serial,
+            to_remove: None,
+            shared: None,
+            pinned: self.pinned,
+            base: self.base,
+            logical_len: self.logical_len,
         }
+    }
 
-        buddy::tidy(&mut self.blocks, self.root);
+    /// `log2` of the size of the block backing this allocation. Panics if the block no longer
+    /// exists in `bookkeeping`, like after a reset.
+    pub fn size_class(&self, bookkeeping: &BuddyBookkeeping) -> u32 {
+        let index = bookkeeping.block_index(self.index).unwrap();
+
+        bookkeeping.blocks[index].range.len().ilog2()
     }
 
-    pub fn tidy_gas(&mut self, gas: usize) {
-        let mut gas = gas;
+    /// The largest power of two the start of the range (including any base offset) is a
+    /// multiple of, up to the size of the whole arena. Blocks are aligned to their own size,
+    /// so for anything but the pieces of a split allocation, or a base offset that isn't a
+    /// multiple of the block size, this is at least the block size.
+    pub fn natural_alignment(&self, bookkeeping: &BuddyBookkeeping) -> usize {
+        let size = bookkeeping.blocks[bookkeeping.root].range.len();
 
-        while let Ok(index) = self.to_remove_receiver.try_recv() {
-            if gas == 0 {
-                return;
-            }
+        match self.range().start {
+            0 => size,
+            start => (1 << start.trailing_zeros()).min(size),
+        }
+    }
+
+    /// Marks this allocation as one that must stay where it is, so `BuddyArena::migrate`
+    /// refuses to move it
+    pub fn pin(&mut self) {
+        self.pinned = true;
+    }
 
-            buddy::dealloc(&mut self.blocks, index);
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
 
-            gas -= 1;
+    /// How many elements at the start of the range hold data. That's all of them, unless this
+    /// came from `BuddyArena::alloc_padded`.
+    pub fn logical_len(&self) -> usize {
+        self.logical_len.unwrap_or(self.range.len())
+    }
+
+    /// Turns this into a handle that can be cloned, freeing the block once the last clone is
+    /// dropped
+    pub fn into_shared(self) -> SharedAllocation {
+        SharedAllocation(Arc::new(self))
+    }
+
+    /// A non-owning handle that can view this allocation for as long as it's still alive
+    pub fn downgrade(&self) -> WeakAllocation {
+        WeakAllocation {
+            index: self.index,
+            range: self.range.clone(),
+            serial: self.serial,
         }
+    }
 
-        buddy::tidy_gas(&mut self.blocks, self.root, &mut gas);
+    /// Splits the range at `mid` (relative to the start of the allocation). Both halves keep
+    /// the underlying block alive, and it's only freed once both have been dropped.
+    pub fn split_at(mut self, mid: usize) -> (Allocation, Allocation) {
+        assert!(mid <= self.range.len());
+
+        let shared = self.shared.get_or_insert_with(|| Arc::new(())).clone();
+        let logical_len = self.logical_len;
+        let mid_offset = mid;
+        let mid = self.range.start + mid;
+
+        let first = Allocation {
+            index: self.index,
+            range: self.range.start..mid,
+            serial: self.serial,
+            to_remove: self.to_remove.clone(),
+            shared: Some(shared.clone()),
+            pinned: self.pinned,
+            base: self.base,
+            logical_len: logical_len.map(|len| len.min(mid_offset)),
+        };
+
+        let second = Allocation {
+            index: self.index,
+            range: mid..self.range.end,
+            serial: self.serial,
+            to_remove: self.to_remove.clone(),
+            shared: Some(shared),
+            pinned: self.pinned,
+            base: self.base,
+            logical_len: logical_len.map(|len| len.saturating_sub(mid_offset)),
+        };
+
+        // `self` still holds a share here, so dropping it won't free the block
+        (first, second)
     }
+}
 
-    pub fn tidy_timed(&mut self, deadline: Instant) {
-        while let Ok(index) = self.to_remove_receiver.try_recv() {
-            if Instant::now() >= deadline {
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            if Arc::into_inner(shared).is_none() {
+                // another piece of this allocation is still alive
                 return;
             }
+        }
 
-            buddy::dealloc(&mut self.blocks, index);
+        if let Some(to_remove) = &self.to_remove {
+            to_remove.send(self.index);
         }
+    }
+}
 
-        buddy::tidy_timed(&mut self.blocks, self.root, deadline);
+/// Obtained from `Allocation::downgrade`. Doesn't keep the block alive, and stops viewing
+/// anything once the allocation has been freed.
+#[derive(Debug, Clone)]
+pub struct WeakAllocation {
+    index: Index,
+    range: Range<usize>,
+    serial: u64,
+}
+
+/// Obtained from `Allocation::into_shared`. Every clone keeps the block alive, and it's freed
+/// when the last one is dropped. Only gives read access, through `BuddyArena::view_shared`.
+#[derive(Debug, Clone)]
+pub struct SharedAllocation(Arc<Allocation>);
+
+impl SharedAllocation {
+    pub fn range(&self) -> Range<usize> {
+        self.0.range()
     }
 }
 
-pub struct BuddyArena<T> {
-    elements: Box<[T]>,
-    bookkeeping: BuddyBookkeeping,
+/// An occupied block with no owner, from `BuddyBookkeeping::reconstruct_handles`. Nothing
+/// happens when it's dropped; it has to be given back with `BuddyBookkeeping::free_raw`.
+#[derive(Debug)]
+pub struct RawAllocation {
+    index: Index,
+    range: Range<usize>,
 }
 
-impl<T> BuddyArena<T> {
-    pub fn new(size: usize, min_block_size: usize, max_block_size: usize) -> BuddyArena<T>
-    where
-        T: Default,
-    {
-        let elements_vec: Vec<T> = repeat_with(|| T::default()).take(size).collect();
+impl RawAllocation {
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+}
 
-        BuddyArena {
-            elements: elements_vec.into(),
-            bookkeeping: BuddyBookkeeping::new(size, min_block_size, max_block_size),
-        }
+/// Space that's been set aside but not handed out yet. It must either be committed into an
+/// `Allocation` or cancelled; dropping it cancels it.
+#[derive(Debug)]
+pub struct Reservation(Allocation);
+
+impl Reservation {
+    pub fn range(&self) -> Range<usize> {
+        self.0.range()
     }
 
-    pub fn bookkeeping(&self) -> &BuddyBookkeeping {
-        &self.bookkeeping
+    pub fn commit(self) -> Allocation {
+        self.0
     }
 
-    pub fn view(&self, a: &Allocation) -> &[T] {
-        &self.elements[a.range()]
+    pub fn cancel(self) {
+        // dropping the inner allocation frees the block
     }
+}
 
-    pub fn view_mut(&mut self, a: &Allocation) -> &mut [T] {
-        &mut self.elements[a.range()]
+/// An allocation whose range is guaranteed to start at a multiple of `ALIGN`
+#[derive(Debug)]
+pub struct AlignedAllocation<const ALIGN: usize>(Allocation);
+
+impl<const ALIGN: usize> AlignedAllocation<ALIGN> {
+    pub fn into_inner(self) -> Allocation {
+        self.0
     }
+}
 
-    pub fn alloc(&mut self, count: usize) -> Option<Allocation> {
-        self.bookkeeping.alloc(count)
+impl<const ALIGN: usize> Deref for AlignedAllocation<ALIGN> {
+    type Target = Allocation;
+
+    fn deref(&self) -> &Allocation {
+        &self.0
     }
+}
 
-    pub fn tidy(&mut self) {
-        self.bookkeeping.tidy();
+/// A read-only copy of a bookkeeping's tree, from `BuddyBookkeeping::freeze`. Dereferences
+/// to a `BuddyBookkeeping` for all of its queries, but can't allocate or tidy.
+pub struct FrozenBuddyBookkeeping(BuddyBookkeeping);
+
+impl Deref for FrozenBuddyBookkeeping {
+    type Target = BuddyBookkeeping;
+
+    fn deref(&self) -> &BuddyBookkeeping {
+        &self.0
     }
+}
 
-    pub fn tidy_gas(&mut self, gas: usize) {
-        self.bookkeeping.tidy_gas(gas);
+impl FrozenBuddyBookkeeping {
+    /// Like `BuddyBookkeeping::max_coalescible_free`, counting the frees that were pending
+    /// when the copy was made
+    pub fn max_coalescible_free(&self) -> usize {
+        self.0.coalescible_free()
     }
+}
 
-    pub fn tidy_timed(&mut self, deadline: Instant) {
-        self.bookkeeping.tidy_timed(deadline);
+/// One leaf block (or, when merging, a run of free leaf blocks) in address order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutEntry {
+    pub range: Range<usize>,
+    pub occupied: bool,
+}
+
+/// What a block is being used for, as seen by `BuddyBookkeeping::try_walk`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    Available,
+    Occupied,
+    Split,
+}
+
+/// An allocation that couldn't be made yet, to retry with `BuddyBookkeeping::poll_pending`
+/// once space has been freed. Doesn't hold on to anything in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingAlloc {
+    count: usize,
+}
+
+impl PendingAlloc {
+    pub fn count(&self) -> usize {
+        self.count
     }
 }
 
-#[test]
-fn test() {
-    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+/// Allocations from `BuddyBookkeeping::scope` that are all freed together once this is dropped,
+/// without any handles to keep track of. A tidy afterwards merges them back.
+pub struct Scope<'a> {
+    bookkeeping: &'a mut BuddyBookkeeping,
+    indices: Vec<Index>,
+}
 
-    let a1 = arena.alloc(64).unwrap();
-    let a2 = arena.alloc(24).unwrap();
-    let a3 = arena.alloc(2).unwrap();
-    let a4 = arena.alloc(7).unwrap();
-    let a5 = arena.alloc(31).unwrap();
-    let a6 = arena.alloc(60).unwrap();
+impl Scope<'_> {
+    /// Hands back only the range, since the allocation belongs to the scope
+    pub fn alloc(&mut self, count: usize) -> Option<Range<usize>> {
+        let allocation = self.bookkeeping.alloc(count)?;
+        let range = allocation.range();
 
-    println!("{:#?}", prettify(arena.bookkeeping()));
+        self.indices.extend(allocation.disown());
 
-    let string = "foobar";
+        Some(range)
+    }
 
-    let a = arena.alloc(string.bytes().len()).unwrap();
-    let view = arena.view_mut(&a);
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
 
-    view.copy_from_slice(string.as_bytes());
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
 
-    let str_view = std::str::from_utf8(view).unwrap();
+    pub fn bookkeeping(&self) -> &BuddyBookkeeping {
+        self.bookkeeping
+    }
+}
 
-    dbg!(str_view);
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        for index in self.indices.drain(..) {
+            self.bookkeeping.dealloc(index);
+        }
+    }
+}
+
+/// Saved tree state from `BuddyBookkeeping::checkpoint`, to go back to with `rewind`
+pub struct Checkpoint {
+    blocks: Arena<Block>,
+    root: Index,
+    generation_offset: u64,
+    used_bytes: usize,
+    live_allocations: usize,
+    recycle: Vec<Recycle>,
+    stack: Vec<Index>,
+}
+
+/// Running totals from `BuddyBookkeeping::stats`, kept since it was made or since the last
+/// `reset_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    pub attempted: u64,
+    pub succeeded: u64,
+    /// Asked for nothing, or for more than `max_block_size`
+    pub failed_too_large: u64,
+    /// There was no free block big enough, or a limit on allocations was hit
+    pub failed_out_of_space: u64,
+    pub tidies: u64,
+    /// Pairs of buddies merged back together by tidying
+    pub merges: u64,
+}
+
+/// Ring buffer of how long recent tree searches took
+#[derive(Clone)]
+struct Latencies {
+    samples: Vec<Duration>,
+    capacity: usize,
+    /// Where the next sample goes once the buffer is full
+    next: usize,
+}
+
+impl Latencies {
+    fn record(&mut self, sample: Duration) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+        } else if self.capacity > 0 {
+            self.samples[self.next] = sample;
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+}
+
+/// Freed blocks of one size, kept aside so `alloc` can hand them straight back out
+#[derive(Default, Clone)]
+struct Recycle {
+    capacity: usize,
+    blocks: Vec<Index>,
+}
+
+struct Pressure {
+    threshold: f64,
+    callback: Box<dyn FnMut(f64) + Send>,
+    /// Whether usage is currently above the threshold, so the callback fires once per crossing
+    above: bool,
+}
+
+pub struct BuddyBookkeeping {
+    pub(crate) blocks: Arena<Block>,
+    pub(crate) root: Index,
+    /// Added to the generation of every index handed out in a handle, so that rebuilding
+    /// `blocks` from scratch can't reuse an index an old handle still holds
+    generation_offset: u64,
+    to_remove_sender: Reclaim,
+    to_remove_receiver: ReclaimQueue,
+    /// Frees that read-only queries have already taken off the channel
+    received: RefCell<Vec<Index>>,
+    min_block_size: usize,
+    max_block_size: usize,
+    /// From `new_aligned`, or `min_block_size` if it wasn't given
+    base_alignment: usize,
+    used_bytes: usize,
+    /// Handed out to each new allocation
+    next_serial: u64,
+    live_allocations: usize,
+    max_allocations: Option<usize>,
+    reject_max_class: bool,
+    /// Bit `n` is set when blocks of `2^n` can't be handed out
+    frozen_classes: u64,
+    /// How many more allocations are allowed to succeed
+    #[cfg(feature = "test-util")]
+    fail_after: usize,
+    pressure: Option<Pressure>,
+    latencies: Option<Latencies>,
+    /// Indexed by size class, starting from `min_block_size`
+    recycle: Vec<Recycle>,
+    /// Blocks handed out by `alloc_stack`, most recent last
+    stack: Vec<Index>,
+    /// Post-order traversal stack for `tidy_step`, as `(block, children_visited)`
+    tidy_cursor: Vec<(Index, bool)>,
+    /// User data attached by `alloc_with_meta`, dropped when the block is freed and no
+    /// frozen copy still holds it
+    meta: HashMap<Index, Arc<dyn Any + Send + Sync>>,
+    base_offset: usize,
+    /// How much of the tree is handed out, from `new_region`. Everything past it is occupied
+    /// for good, and counted as used.
+    region_len: usize,
+    stats: AllocStats,
+    /// Freed blocks at least this big are merged with their buddies straight away
+    eager_coalesce_above: usize,
+}
+
+impl BuddyBookkeeping {
+    pub fn new(size: usize, min_block_size: usize, max_block_size: usize) -> BuddyBookkeeping {
+        let (sender, receiver) = mpsc::channel();
+
+        BuddyBookkeeping::with_queue(
+            size,
+            min_block_size,
+            max_block_size,
+            Reclaim::Channel(sender),
+            ReclaimQueue::Channel(receiver),
+        )
+    }
+
+    /// Like `new`, but frees are queued on the current thread without going through a
+    /// channel, so dropping an allocation is just a push onto a `Vec`. The bookkeeping and
+    /// its allocations have to stay on this thread: allocations dropped anywhere else are
+    /// leaked, as are all frees if the bookkeeping itself is moved.
+    pub fn new_single_threaded(
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> BuddyBookkeeping {
+        let id = NEXT_LOCAL_QUEUE.fetch_add(1, Ordering::Relaxed);
+        LOCAL_QUEUES.with(|queues| queues.borrow_mut().insert(id, Vec::new()));
+
+        BuddyBookkeeping::with_queue(
+            size,
+            min_block_size,
+            max_block_size,
+            Reclaim::Local(id),
+            ReclaimQueue::Local(id),
+        )
+    }
+
+    /// Manages `base..base + size` of some external region, like a GPU heap, handing out
+    /// allocations with ranges inside it. Neither has to be a power of two: the tree is
+    /// rounded up to one (and to at least `max_block_size`), and everything past `size`,
+    /// rounded down to `min_block_size`, is occupied for good.
+    pub fn new_region(
+        base: usize,
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> BuddyBookkeeping {
+        let tree_size = size
+            .checked_next_power_of_two()
+            .expect("region is too big for a power of two tree")
+            .max(max_block_size);
+
+        let mut bookkeeping = BuddyBookkeeping::new(tree_size, min_block_size, max_block_size);
+        bookkeeping.region_len = size - size % min_block_size;
+        bookkeeping.occupy_tail();
+        bookkeeping.set_base_offset(base);
+
+        bookkeeping
+    }
+
+    /// Occupies the blocks covering the tree past `region_len`, biggest first
+    fn occupy_tail(&mut self) {
+        let size = self.blocks[self.root].range.len();
+
+        let mut start = self.region_len;
+        while start < size {
+            let aligned = match start {
+                0 => size,
+                start => 1 << start.trailing_zeros(),
+            };
+            let len = aligned.min(1 << (size - start).ilog2());
+
+            let index = buddy::alloc_where(&mut self.blocks, self.root, len, &|range| {
+                range.contains(&start)
+            })
+            .unwrap();
+            // no handle has this serial, so nothing can view or free the block
+            self.blocks[index].serial = u64::MAX;
+            self.used_bytes += len;
+
+            start += len;
+        }
+    }
+
+    fn with_queue(
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+        sender: Reclaim,
+        receiver: ReclaimQueue,
+    ) -> BuddyBookkeeping {
+        assert!(is_pow_of_two(size));
+        assert!(is_pow_of_two(min_block_size));
+        assert!(is_pow_of_two(max_block_size));
+        assert!(max_block_size <= size);
+        assert!(min_block_size <= max_block_size);
+
+        let mut new_arena = Arena::new();
+        let root = new_arena.insert(Block::available(0..size));
+
+        BuddyBookkeeping {
+            to_remove_sender: sender,
+            to_remove_receiver: receiver,
+            received: RefCell::new(Vec::new()),
+            blocks: new_arena,
+            root,
+            generation_offset: 0,
+            min_block_size,
+            max_block_size,
+            base_alignment: min_block_size,
+            used_bytes: 0,
+            next_serial: 0,
+            live_allocations: 0,
+            max_allocations: None,
+            reject_max_class: false,
+            frozen_classes: 0,
+            #[cfg(feature = "test-util")]
+            fail_after: usize::MAX,
+            pressure: None,
+            latencies: None,
+            recycle: Vec::new(),
+            stack: Vec::new(),
+            tidy_cursor: Vec::new(),
+            meta: HashMap::new(),
+            base_offset: 0,
+            region_len: size,
+            stats: AllocStats::default(),
+            eager_coalesce_above: usize::MAX,
+        }
+    }
+
+    /// How many allocations are holding on to a block, as of the last tidy
+    pub fn live_allocations(&self) -> usize {
+        self.live_allocations
+    }
+
+    /// Shifts the ranges of allocations made from now on by `base_offset`, for handing out
+    /// ranges of a region that doesn't start at 0. The tree itself, and every other query on
+    /// the bookkeeping, still works in `0..size`. Any offset works, but blocks are only
+    /// aligned to their size once they're shifted if it's a multiple of `max_block_size`;
+    /// `alloc_aligned` accounts for it either way. Panics if the shifted ranges wouldn't fit
+    /// in a `usize`.
+    pub fn set_base_offset(&mut self, base_offset: usize) {
+        assert!(
+            base_offset.checked_add(self.region_len).is_some(),
+            "base offset pushes the end of the arena past usize::MAX"
+        );
+
+        self.base_offset = base_offset;
+    }
+
+    pub fn base_offset(&self) -> usize {
+        self.base_offset
+    }
+
+    /// Freed blocks of at least `size` are merged back up the tree as soon as they're taken
+    /// off the queue, while smaller ones are left for `tidy`. Small blocks tend to be
+    /// reallocated soon after, so merging them is often wasted work. `usize::MAX`, the
+    /// default, leaves everything for `tidy`.
+    pub fn set_eager_coalesce_above(&mut self, size: usize) {
+        self.eager_coalesce_above = size;
+    }
+
+    pub fn eager_coalesce_above(&self) -> usize {
+        self.eager_coalesce_above
+    }
+
+    /// Makes allocations that would take up a whole `max_block_size` block fail as if they
+    /// were too big, so no single allocation can take one of the biggest blocks. Callers have
+    /// to split requests that big up themselves.
+    pub fn set_reject_max_class(&mut self, reject: bool) {
+        self.reject_max_class = reject;
+    }
+
+    pub fn rejects_max_class(&self) -> bool {
+        self.reject_max_class
+    }
+
+    /// Stops blocks of `2^class` from being handed out, reserving that size for something
+    /// else, like a separate pool. Allocations that round up to it fail. Blocks of that size
+    /// are still split to make smaller ones. Classes too big for any block do nothing.
+    pub fn freeze_class(&mut self, class: u32) {
+        self.frozen_classes |= 1u64.checked_shl(class).unwrap_or(0);
+    }
+
+    pub fn thaw_class(&mut self, class: u32) {
+        self.frozen_classes &= !1u64.checked_shl(class).unwrap_or(0);
+    }
+
+    pub fn is_class_frozen(&self, class: u32) -> bool {
+        self.frozen_classes & 1u64.checked_shl(class).unwrap_or(0) != 0
+    }
+
+    /// Whether a block of `size` may be handed out at all, going by `freeze_class` and
+    /// `set_reject_max_class`
+    fn allows_block(&self, size: usize) -> bool {
+        let rejected = self.reject_max_class && size >= self.max_block_size;
+
+        !rejected && !self.is_class_frozen(size.ilog2())
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = AllocStats::default();
+    }
+
+    fn record_too_large<A>(&mut self) -> Option<A> {
+        self.stats.attempted += 1;
+        self.stats.failed_too_large += 1;
+
+        None
+    }
+
+    fn record_attempt<A>(&mut self, allocation: Option<A>) -> Option<A> {
+        self.stats.attempted += 1;
+        match allocation {
+            Some(_) => self.stats.succeeded += 1,
+            None => self.stats.failed_out_of_space += 1,
+        }
+
+        allocation
+    }
+
+    /// Counts a tidy that started with `nodes_before` blocks in the tree
+    fn record_tidy(&mut self, nodes_before: usize) {
+        self.stats.tidies += 1;
+        // every merge removes both children
+        self.stats.merges += (nodes_before.saturating_sub(self.blocks.len()) / 2) as u64;
+    }
+
+    /// Caps how many allocations can be live at once, regardless of how much space is left.
+    /// `None` means no limit.
+    pub fn set_max_allocations(&mut self, max: Option<usize>) {
+        self.max_allocations = max;
+    }
+
+    /// Lets the next `n` allocations through, and makes every one after that fail whether or
+    /// not there's space. `usize::MAX` turns this off again.
+    #[cfg(feature = "test-util")]
+    pub fn set_fail_after(&mut self, n: usize) {
+        self.fail_after = n;
+    }
+
+    /// Starts timing every tree search `alloc` does, keeping the last `capacity` samples.
+    /// `None` stops timing and throws the samples away.
+    pub fn set_latency_tracking(&mut self, capacity: Option<usize>) {
+        self.latencies = capacity.map(|capacity| Latencies {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        });
+    }
+
+    /// The most recent search times, in no particular order once the buffer has filled up.
+    /// Empty unless latency tracking is on.
+    pub fn recent_alloc_latencies(&self) -> &[Duration] {
+        self.latencies
+            .as_ref()
+            .map_or(&[], |latencies| &latencies.samples)
+    }
+
+    /// Same as `new`, but the sizes are already known to be powers of two
+    pub fn new_pow2(size: Pow2, min_block_size: Pow2, max_block_size: Pow2) -> BuddyBookkeeping {
+        BuddyBookkeeping::new(size.get(), min_block_size.get(), max_block_size.get())
+    }
+
+    /// Same as `new`, but checks that every allocation will start at a multiple of
+    /// `base_alignment`. Leaves are never smaller than `min_block_size`, and blocks are aligned
+    /// to their size, so that holds as long as it's a power of two no bigger than the minimum.
+    pub fn new_aligned(
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+        base_alignment: usize,
+    ) -> BuddyBookkeeping {
+        assert!(is_pow_of_two(base_alignment));
+        assert!(base_alignment <= min_block_size);
+
+        let mut bookkeeping = BuddyBookkeeping::new(size, min_block_size, max_block_size);
+        bookkeeping.base_alignment = base_alignment;
+
+        bookkeeping
+    }
+
+    /// The alignment asked for with `new_aligned`, or `min_block_size` otherwise. Every
+    /// allocation starts at a multiple of this in the tree, whatever size was asked for.
+    pub fn base_alignment(&self) -> usize {
+        self.base_alignment
+    }
+
+    /// Total size of all occupied blocks (including rounding), as of the last tidy
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    /// The index of every block in the tree, including split ones, in no particular order.
+    /// Useful for keeping side tables keyed by block.
+    pub fn block_indices(&self) -> impl Iterator<Item = Index> + '_ {
+        self.blocks
+            .iter()
+            .map(|(index, _)| self.handle_index(index))
+    }
+
+    /// The index a handle holds for the block at `index`
+    fn handle_index(&self, index: Index) -> Index {
+        let (slot, generation) = index.into_raw_parts();
+
+        Index::from_raw_parts(slot, generation + self.generation_offset)
+    }
+
+    /// The block a handle's index refers to, or `None` if it's from before `blocks` was last
+    /// rebuilt. The block itself may have been removed since.
+    fn block_index(&self, handle: Index) -> Option<Index> {
+        let (slot, generation) = handle.into_raw_parts();
+
+        Some(Index::from_raw_parts(
+            slot,
+            generation.checked_sub(self.generation_offset)?,
+        ))
+    }
+
+    fn handle_block(&self, handle: Index) -> Option<&Block> {
+        self.blocks.get(self.block_index(handle)?)
+    }
+
+    /// Every leaf block, left to right
+    pub(crate) fn leaves(&self) -> impl Iterator<Item = &Block> + '_ {
+        let mut stack = vec![self.root];
+
+        std::iter::from_fn(move || loop {
+            let block = &self.blocks[stack.pop()?];
+
+            match block.state {
+                BlockState::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                _ => return Some(block),
+            }
+        })
+    }
+
+    /// The free blocks, left to right, that `alloc(count)` could be carved out of, as of the
+    /// last tidy
+    pub fn free_blocks_fitting(&self, count: usize) -> Vec<Range<usize>> {
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return Vec::new();
+        };
+
+        self.leaves()
+            .filter(|block| {
+                matches!(block.state, BlockState::Available) && block.range.len() >= best_size
+            })
+            .map(|block| block.range.clone())
+            .collect()
+    }
+
+    /// Everything about the current state in one human-readable report, for pasting into bug
+    /// reports. Counts are as of the last tidy.
+    pub fn dump_report(&self) -> String {
+        use std::fmt::Write;
+
+        let size = self.blocks[self.root].range.len();
+
+        let mut free_by_size: Vec<(usize, usize)> = Vec::new();
+        for block in self.leaves() {
+            if matches!(block.state, BlockState::Available) {
+                let len = block.range.len();
+                match free_by_size.iter_mut().find(|(size, _)| *size == len) {
+                    Some((_, count)) => *count += 1,
+                    None => free_by_size.push((len, 1)),
+                }
+            }
+        }
+        free_by_size.sort_unstable();
+        let largest_free = free_by_size.last().map_or(0, |&(size, _)| size);
+
+        // writing to a String can't fail
+        let mut report = String::new();
+        let _ = writeln!(report, "size: {size}");
+        let _ = writeln!(report, "used bytes: {}", self.used_bytes);
+        let _ = writeln!(report, "free bytes: {}", size - self.used_bytes);
+        let _ = writeln!(report, "live allocations: {}", self.live_allocations);
+        let _ = writeln!(
+            report,
+            "internal fragmentation: {}",
+            self.internal_fragmentation()
+        );
+        let _ = writeln!(report, "largest free block: {largest_free}");
+        let _ = writeln!(report, "free blocks by size:");
+        for (size, count) in free_by_size {
+            let _ = writeln!(report, "  {size}: {count}");
+        }
+        let _ = writeln!(report, "tree:");
+        let _ = write!(report, "{}", crate::pretty_print::prettify(self));
+
+        report
+    }
+
+    /// The blocks, occupied or free, right before and after the one holding `alloc`, in
+    /// address order
+    pub fn neighbors(&self, alloc: &Allocation) -> (Option<Range<usize>>, Option<Range<usize>>) {
+        let mut before = None;
+        let mut entries = self.layout(false);
+
+        for entry in entries.by_ref() {
+            if entry.range.contains(&alloc.range.start) {
+                break;
+            }
+
+            before = Some(entry.range);
+        }
+
+        (before, entries.next().map(|entry| entry.range))
+    }
+
+    /// Every stretch of contiguous free space, left to right. Neighbouring free blocks are
+    /// joined even when they aren't buddies, so a run can be bigger than any one block.
+    pub fn free_runs(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.layout(true)
+            .filter(|entry| !entry.occupied)
+            .map(|entry| entry.range)
+    }
+
+    /// Whether every element in `range` lies inside an occupied block, with no free gaps
+    pub fn is_range_occupied(&self, range: Range<usize>) -> bool {
+        if range.end > self.blocks[self.root].range.len() {
+            return false;
+        }
+
+        self.leaves()
+            .filter(|block| block.range.start < range.end && range.start < block.range.end)
+            .all(|block| matches!(block.state, BlockState::Occupied))
+    }
+
+    /// Walks the arena from start to end, yielding every occupied and free leaf block. With
+    /// `merge_free`, neighbouring free blocks are combined into a single entry.
+    pub fn layout(&self, merge_free: bool) -> impl Iterator<Item = LayoutEntry> + '_ {
+        let mut entries = self
+            .leaves()
+            .map(|block| LayoutEntry {
+                range: block.range.clone(),
+                occupied: matches!(block.state, BlockState::Occupied),
+            })
+            .peekable();
+
+        std::iter::from_fn(move || {
+            let mut entry = entries.next()?;
+
+            if merge_free && !entry.occupied {
+                while let Some(next) = entries.next_if(|next| !next.occupied) {
+                    entry.range.end = next.range.end;
+                }
+            }
+
+            Some(entry)
+        })
+    }
+
+    /// Visits every block in the tree, parents before their children and first children
+    /// before second ones, until `f` breaks. Returns what it broke with, or `None` if it
+    /// visited everything.
+    pub fn try_walk<B, F>(&self, mut f: F) -> Option<B>
+    where
+        F: FnMut(&Range<usize>, BlockKind) -> ControlFlow<B>,
+    {
+        let mut stack = vec![self.root];
+
+        while let Some(index) = stack.pop() {
+            let block = &self.blocks[index];
+
+            let kind = match block.state {
+                BlockState::Available => BlockKind::Available,
+                BlockState::Occupied => BlockKind::Occupied,
+                BlockState::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+
+                    BlockKind::Split
+                }
+            };
+
+            if let ControlFlow::Break(value) = f(&block.range, kind) {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    /// How much more free space would sit in one run at the end of the arena if every
+    /// occupied block were packed towards the start. Zero means compacting wouldn't help.
+    pub fn compaction_benefit(&self) -> usize {
+        let size = self.blocks[self.root].range.len();
+
+        let mut occupied = 0;
+        let mut tail_free = 0;
+
+        for entry in self.layout(true) {
+            if entry.occupied {
+                occupied += entry.range.len();
+                tail_free = 0;
+            } else {
+                tail_free = entry.range.len();
+            }
+        }
+
+        // blocks are powers of two, so packing them largest first leaves no gaps
+        size - occupied - tail_free
+    }
+
+    /// The largest block an allocation could get after a full `tidy`, counting frees that
+    /// haven't been processed yet. Doesn't change the tree.
+    pub fn max_coalescible_free(&self) -> usize {
+        self.received
+            .borrow_mut()
+            .extend(std::iter::from_fn(|| self.to_remove_receiver.try_recv()));
+
+        self.coalescible_free()
+    }
+
+    /// `max_coalescible_free`, counting only the frees already taken off the channel
+    fn coalescible_free(&self) -> usize {
+        /// Returns whether the whole block would be free, and the largest free block inside it
+        fn walk(blocks: &Arena<Block>, index: Index, pending: &HashSet<Index>) -> (bool, usize) {
+            let block = &blocks[index];
+
+            match block.state {
+                BlockState::Available => (true, block.range.len()),
+                BlockState::Occupied if pending.contains(&index) => (true, block.range.len()),
+                BlockState::Occupied => (false, 0),
+                BlockState::Split(first, second) => {
+                    let (first_free, first_largest) = walk(blocks, first, pending);
+                    let (second_free, second_largest) = walk(blocks, second, pending);
+
+                    if first_free && second_free {
+                        (true, block.range.len())
+                    } else {
+                        (false, first_largest.max(second_largest))
+                    }
+                }
+            }
+        }
+
+        // a full tidy also returns recycled blocks to the tree
+        let pending: HashSet<Index> = self
+            .received
+            .borrow()
+            .iter()
+            .filter_map(|&handle| self.block_index(handle))
+            .chain(self.recycle.iter().flat_map(|r| r.blocks.iter().copied()))
+            .collect();
+
+        walk(&self.blocks, self.root, &pending)
+            .1
+            .min(self.max_block_size)
+    }
+
+    /// The chain of blocks from the root down to `index` (inclusive), or `None` if `index`
+    /// isn't in the tree
+    pub fn path_to(&self, index: Index) -> Option<Vec<Index>> {
+        let path = self.block_path(self.block_index(index)?)?;
+
+        Some(
+            path.into_iter()
+                .map(|index| self.handle_index(index))
+                .collect(),
+        )
+    }
+
+    /// Same as `path_to`, but with the tree's own indices rather than handles'
+    fn block_path(&self, index: Index) -> Option<Vec<Index>> {
+        let target = self.blocks.get(index)?.range.clone();
+
+        let mut path = vec![self.root];
+        let mut current = self.root;
+
+        while current != index {
+            match self.blocks[current].state {
+                BlockState::Split(first, second) => {
+                    current = if target.start < self.blocks[first].range.end {
+                        first
+                    } else {
+                        second
+                    };
+
+                    path.push(current);
+                }
+                _ => return None,
+            }
+        }
+
+        Some(path)
+    }
+
+    /// Total slack between what was asked for and the block sizes handed out, across every
+    /// block held by an allocation. Recycled blocks aren't counted.
+    pub fn internal_fragmentation(&self) -> usize {
+        self.owned_blocks()
+            .map(|(_, block)| block.range.len() - block.requested)
+            .sum()
+    }
+
+    /// How many more bytes the blocks held by allocations would take up if every one of them
+    /// had been aligned to `align`, i.e. rounded up to a block at least `align` big
+    pub fn alignment_overhead(&self, align: usize) -> usize {
+        assert!(is_pow_of_two(align));
+
+        self.owned_blocks()
+            .map(|(_, block)| align.saturating_sub(block.range.len()))
+            .sum()
+    }
+
+    /// Scales every block from elements of `from` bytes to elements of `to` bytes. Leaves the
+    /// tree untouched and returns false if anything doesn't divide evenly into a power of two.
+    fn rescale(&mut self, from: usize, to: usize) -> bool {
+        let scales_to_pow2 = |x| rescale(x, from, to).is_some_and(is_pow_of_two);
+
+        let sizes_ok = scales_to_pow2(self.blocks[self.root].range.len())
+            && scales_to_pow2(self.min_block_size)
+            && scales_to_pow2(self.max_block_size)
+            && rescale(self.base_offset, from, to).is_some()
+            && rescale(self.region_len, from, to).is_some();
+
+        let blocks_ok = self.blocks.iter().all(|(_, block)| {
+            rescale(block.range.start, from, to).is_some()
+                && rescale(block.range.end, from, to).is_some()
+                && rescale(block.requested, from, to).is_some()
+        });
+
+        if !(sizes_ok && blocks_ok) {
+            return false;
+        }
+
+        // everything was checked above
+        let scale = |x| rescale(x, from, to).unwrap();
+
+        for (_, block) in self.blocks.iter_mut() {
+            block.range = scale(block.range.start)..scale(block.range.end);
+            block.requested = scale(block.requested);
+        }
+
+        self.min_block_size = scale(self.min_block_size);
+        self.max_block_size = scale(self.max_block_size);
+        self.base_offset = scale(self.base_offset);
+        self.region_len = scale(self.region_len);
+        // an alignment of less than one new element is no promise at all
+        self.base_alignment = rescale(self.base_alignment, from, to)
+            .filter(|&align| is_pow_of_two(align))
+            .unwrap_or(1);
+        self.used_bytes = scale(self.used_bytes);
+
+        true
+    }
+
+    /// Rebuilds the block tree into a compact arena, releasing the capacity left over from
+    /// earlier splits. Every block index changes, so this panics if anything is still
+    /// occupied; drop outstanding allocations and `tidy` first. Recycled blocks are given
+    /// back to the tree.
+    pub fn shrink_bookkeeping(&mut self) {
+        fn copy(
+            old: &Arena<Block>,
+            new: &mut Arena<Block>,
+            index: Index,
+            region_len: usize,
+        ) -> Index {
+            let block = &old[index];
+
+            let state = match block.state {
+                BlockState::Split(first, second) => BlockState::Split(
+                    copy(old, new, first, region_len),
+                    copy(old, new, second, region_len),
+                ),
+                BlockState::Available => BlockState::Available,
+                // past the end of a region, not an allocation
+                BlockState::Occupied if block.range.start >= region_len => BlockState::Occupied,
+                BlockState::Occupied => panic!("can't shrink bookkeeping with live allocations"),
+            };
+
+            new.insert(Block {
+                state,
+                ..block.clone()
+            })
+        }
+
+        self.flush_recycled();
+
+        let mut blocks = Arena::with_capacity(self.blocks.len());
+        self.root = copy(&self.blocks, &mut blocks, self.root, self.region_len);
+
+        // handles from before a reset still hold old indices, which mustn't come back, so
+        // shift the new arena's generations past every one the old arena handed out
+        let latest = self.blocks.insert(Block::available(0..0));
+        let (_, latest_generation) = latest.into_raw_parts();
+        self.generation_offset += latest_generation + 1;
+        self.blocks = blocks;
+
+        // the cursor refers to the old indices
+        self.tidy_cursor.clear();
+    }
+
+    /// Every block size an allocation can be rounded to, smallest first
+    pub fn size_classes(&self) -> impl Iterator<Item = usize> {
+        let max_block_size = self.max_block_size;
+
+        std::iter::successors(Some(self.min_block_size), move |&size| {
+            (size < max_block_size).then_some(size * 2)
+        })
+    }
+
+    /// Rough estimate of the heap used by the block tree itself. Each slot in the underlying
+    /// arena holds a `Block` plus a generation, whether or not it's in use, and slots are never
+    /// given back once the arena has grown.
+    pub fn bookkeeping_bytes(&self) -> usize {
+        self.blocks.capacity() * (std::mem::size_of::<Block>() + std::mem::size_of::<u64>())
+    }
+
+    fn usage(&self) -> f64 {
+        self.used_bytes as f64 / self.blocks[self.root].range.len() as f64
+    }
+
+    /// Calls `callback` with the current usage fraction whenever an allocation pushes usage
+    /// above `threshold`. It fires once per crossing, and rearms once tidying brings usage
+    /// back down to the threshold or below.
+    pub fn set_pressure_callback(&mut self, threshold: f64, callback: Box<dyn FnMut(f64) + Send>) {
+        self.pressure = Some(Pressure {
+            threshold,
+            callback,
+            above: self.usage() > threshold,
+        });
+    }
+
+    /// The block size `alloc` would use for `count` elements: the next power of two, but at
+    /// least `min_block_size`. `None` if that's bigger than `max_block_size` (or equal to it,
+    /// with `set_reject_max_class`), or `count` is 0.
+    pub fn rounded_size_for(&self, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let best_size = count.checked_next_power_of_two()?.max(self.min_block_size);
+
+        let limit = if self.reject_max_class {
+            self.max_block_size / 2
+        } else {
+            self.max_block_size
+        };
+
+        (best_size <= limit).then_some(best_size)
+    }
+
+    pub fn alloc(&mut self, count: usize) -> Option<Allocation> {
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+
+        self.alloc_block(best_size, count)
+    }
+
+    /// Like `alloc`, but fails unless there would still be a free block of at least `reserve`
+    /// afterwards, so a big block needed later isn't split up. This is checked by picking the
+    /// block the same way `alloc` would, trying it on a copy of the tree first. Frees that
+    /// haven't been tidied yet don't count.
+    pub fn alloc_keeping_reserve(&mut self, count: usize, reserve: usize) -> Option<Allocation> {
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+
+        let kept = if !self.can_hand_out(best_size) {
+            false
+        } else if self.recycled_position(best_size, &|_| true).is_some() {
+            // a recycled block doesn't touch the tree
+            has_free_block(&self.blocks, self.root, reserve)
+        } else {
+            let mut scratch = self.blocks.clone();
+
+            buddy::alloc_where(&mut scratch, self.root, best_size, &|_| true).is_some()
+                && has_free_block(&scratch, self.root, reserve)
+        };
+
+        if !kept {
+            return self.record_attempt(None);
+        }
+
+        self.alloc_block(best_size, count)
+    }
+
+    /// Tries to allocate `max_count` elements, and if that doesn't fit, the largest power of
+    /// two below it that does, going no lower than `min_count`
+    pub fn alloc_at_most(&mut self, max_count: usize, min_count: usize) -> Option<Allocation> {
+        assert!(min_count <= max_count);
+
+        if let Some(allocation) = self.alloc(max_count) {
+            return Some(allocation);
+        }
+
+        let mut count = match max_count {
+            0 | 1 => return None,
+            _ => 1 << (max_count - 1).ilog2(),
+        };
+        while count > min_count {
+            if let Some(allocation) = self.alloc(count) {
+                return Some(allocation);
+            }
+
+            count /= 2;
+        }
+
+        self.alloc(min_count)
+    }
+
+    /// Allocates a whole block of exactly `size`, skipping the rounding `alloc` does
+    pub fn alloc_exact(&mut self, size: usize) -> Option<Allocation> {
+        assert!(is_pow_of_two(size));
+        assert!(self.min_block_size <= size && size <= self.max_block_size);
+
+        self.alloc_block(size, size)
+    }
+
+    /// Allocates all of the largest available block, or a `max_block_size` piece of it if it's
+    /// bigger than that, for grabbing as much contiguous space as there is. Sizes that
+    /// `freeze_class` or `set_reject_max_class` rule out are skipped in favour of the next
+    /// size down. Frees that haven't been tidied yet don't count.
+    pub fn alloc_largest(&mut self) -> Option<Allocation> {
+        let largest = self
+            .leaves()
+            .filter(|block| matches!(block.state, BlockState::Available))
+            .map(|block| block.range.len())
+            .max()?;
+
+        let classes: Vec<usize> = self.size_classes().collect();
+        let Some(size) = classes
+            .into_iter()
+            .rev()
+            .find(|&size| size <= largest && self.allows_block(size))
+        else {
+            return self.record_attempt(None);
+        };
+
+        self.alloc_block(size, size)
+    }
+
+    /// Allocates a whole block of `2^class` elements. `None` if that's outside the block size
+    /// limits.
+    pub fn alloc_class(&mut self, class: u32) -> Option<Allocation> {
+        let Some(size) = 1usize.checked_shl(class) else {
+            return self.record_too_large();
+        };
+        if size < self.min_block_size || size > self.max_block_size {
+            return self.record_too_large();
+        }
+
+        self.alloc_block(size, size)
+    }
+
+    /// Allocates `count` elements starting at a multiple of `align`, counting the base offset.
+    /// Blocks are always aligned to their own size in the tree, so with an aligned base offset
+    /// this just makes sure the block is at least `align` big. Otherwise it takes a block that
+    /// sits at the right spot within an `align`, which fails if no block that small can start
+    /// there.
+    pub fn alloc_aligned(&mut self, count: usize, align: usize) -> Option<Allocation> {
+        assert!(is_pow_of_two(align));
+
+        // where in each `align` of the tree the shifted ranges land on a multiple of `align`
+        let target = (align - self.base_offset % align) % align;
+
+        let Some(rounded) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+        let best_size = match target {
+            0 => rounded.max(align),
+            _ => rounded,
+        };
+        if best_size > self.max_block_size {
+            return self.record_too_large();
+        }
+        if !target.is_multiple_of(best_size) {
+            return self.record_attempt(None);
+        }
+
+        // whether the first offset in `range` at `target` within an `align` is inside it
+        let fits = |range: &Range<usize>| {
+            range.start + (target + align - range.start % align) % align < range.end
+        };
+        let allocation = self.find_block(best_size, count, &fits);
+
+        self.record_attempt(allocation)
+    }
+
+    /// Same as `alloc_aligned`, but checks `ALIGN` at compile time and carries it in the type
+    pub fn alloc_aligned_const<const ALIGN: usize>(
+        &mut self,
+        count: usize,
+    ) -> Option<AlignedAllocation<ALIGN>> {
+        const { assert!(ALIGN.is_power_of_two()) };
+
+        self.alloc_aligned(count, ALIGN).map(AlignedAllocation)
+    }
+
+    /// Spreads allocations across `colors` cache colors: prefers a block whose offset, counted
+    /// in blocks of its own size, is `color` modulo `colors`. Falls back to any block if none
+    /// of that color are free.
+    pub fn alloc_colored(
+        &mut self,
+        count: usize,
+        color: usize,
+        colors: usize,
+    ) -> Option<Allocation> {
+        assert!(color < colors);
+
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+
+        // whether a block has room for a `best_size` block of the right color
+        let has_color = |range: &Range<usize>| {
+            let first = range.start / best_size;
+            let slots = range.len() / best_size;
+
+            slots >= colors || (first..first + slots).any(|slot| slot % colors == color)
+        };
+
+        let allocation = self
+            .find_block(best_size, count, &has_color)
+            .or_else(|| self.find_block(best_size, count, &|_| true));
+
+        self.record_attempt(allocation)
+    }
+
+    /// Assumes `best_size` is a power of 2 within the block size limits, and at least `count`
+    fn alloc_block(&mut self, best_size: usize, count: usize) -> Option<Allocation> {
+        let allocation = self.find_block(best_size, count, &|_| true);
+
+        self.record_attempt(allocation)
+    }
+
+    /// Like `alloc_block`, but only uses a block if `fits` accepts it (see
+    /// `buddy::alloc_where`), and doesn't count towards the stats
+    fn find_block(
+        &mut self,
+        best_size: usize,
+        count: usize,
+        fits: &impl Fn(&Range<usize>) -> bool,
+    ) -> Option<Allocation> {
+        if !self.can_hand_out(best_size) {
+            return None;
+        }
+
+        match self.recycled_position(best_size, fits) {
+            Some(position) => {
+                let slot = self.recycle_slot(best_size);
+                let index = self.recycle[slot].blocks.remove(position);
+
+                // recycled blocks are still occupied and counted as used
+                Some(self.hand_out(index, count))
+            }
+            None => self.search_tree(best_size, count, fits),
+        }
+    }
+
+    /// Where the block `find_block` would reuse for `best_size` is in its recycle list, if
+    /// there's one `fits` accepts
+    fn recycled_position(
+        &self,
+        best_size: usize,
+        fits: &impl Fn(&Range<usize>) -> bool,
+    ) -> Option<usize> {
+        self.recycle
+            .get(self.recycle_slot(best_size))?
+            .blocks
+            .iter()
+            .rposition(|&index| fits(&self.blocks[index].range))
+    }
+
+    /// Whether the limits on allocations allow handing out a `best_size` block
+    fn can_hand_out(&self, best_size: usize) -> bool {
+        if self
+            .max_allocations
+            .is_some_and(|max| self.live_allocations >= max)
+        {
+            return false;
+        }
+
+        #[cfg(feature = "test-util")]
+        if self.fail_after == 0 {
+            return false;
+        }
+
+        self.allows_block(best_size)
+    }
+
+    /// The part of `find_block` that carves a new block out of the tree, without looking at
+    /// the recycle lists or the limits
+    fn search_tree(
+        &mut self,
+        best_size: usize,
+        count: usize,
+        fits: &impl Fn(&Range<usize>) -> bool,
+    ) -> Option<Allocation> {
+        let index = match &mut self.latencies {
+            Some(latencies) => {
+                let start = Instant::now();
+                let index = buddy::alloc_where(&mut self.blocks, self.root, best_size, fits);
+                latencies.record(start.elapsed());
+
+                index
+            }
+            None => buddy::alloc_where(&mut self.blocks, self.root, best_size, fits),
+        }?;
+        self.used_bytes += best_size;
+
+        Some(self.hand_out(index, count))
+    }
+
+    /// Fills in a newly occupied block and makes the allocation for it
+    fn hand_out(&mut self, index: Index, count: usize) -> Allocation {
+        #[cfg(feature = "test-util")]
+        if self.fail_after != usize::MAX {
+            self.fail_after -= 1;
+        }
+
+        let usage = self.usage();
+        if let Some(pressure) = &mut self.pressure {
+            if !pressure.above && usage > pressure.threshold {
+                pressure.above = true;
+                (pressure.callback)(usage);
+            }
+        }
+
+        self.stamp(index, count)
+    }
+
+    /// Makes a fresh allocation for an occupied block, without counting it as a new one
+    /// against `fail_after` or the pressure threshold
+    fn stamp(&mut self, index: Index, count: usize) -> Allocation {
+        self.live_allocations += 1;
+        self.blocks[index].requested = count;
+        self.blocks[index].serial = self.next_serial;
+        self.next_serial += 1;
+
+        Allocation {
+            index: self.handle_index(index),
+            range: (self.blocks[index].range.start)..(self.blocks[index].range.start + count),
+            serial: self.blocks[index].serial,
+            to_remove: Some(self.to_remove_sender.clone()),
+            shared: None,
+            pinned: false,
+            base: self.base_offset,
+            logical_len: None,
+        }
+    }
+
+    /// Hands out a new allocation of `count` elements in the same block as `allocation`, with a
+    /// new serial so the old handle's copies are stale. Hands it back if `count` doesn't fit
+    /// the block, or the block is shared with other pieces.
+    fn restamp(&mut self, allocation: Allocation, count: usize) -> Result<Allocation, Allocation> {
+        let Some(index) = self
+            .block_index(allocation.index)
+            .filter(|&index| self.is_occupied(index))
+        else {
+            return Err(allocation);
+        };
+
+        let fits = (1..=self.blocks[index].range.len()).contains(&count);
+        if !fits || allocation.shared.is_some() {
+            return Err(allocation);
+        }
+
+        let pinned = allocation.pinned;
+        allocation.disown();
+        self.live_allocations -= 1;
+
+        // the block was already allocated, so this isn't a new allocation
+        let mut restamped = self.stamp(index, count);
+        restamped.pinned = pinned;
+
+        Ok(restamped)
+    }
+
+    /// Instead of rounding `count` up to one block, covers it with a run of smaller blocks
+    /// next to each other, biggest first, leaving the rest of the rounded block free for other
+    /// allocations. For example, 24 elements take a 16 block and an 8 block, rather than
+    /// wasting 8 of a 32 block.
+    pub fn alloc_split_tail(&mut self, count: usize) -> Option<Vec<Allocation>> {
+        /// The `(block size, count)` of each piece covering `count` of a `len` block
+        fn carve(
+            len: usize,
+            count: usize,
+            min_block_size: usize,
+            pieces: &mut Vec<(usize, usize)>,
+        ) {
+            if count == len || len == min_block_size {
+                pieces.push((len, count));
+            } else if count <= len / 2 {
+                carve(len / 2, count, min_block_size, pieces);
+            } else {
+                pieces.push((len / 2, len / 2));
+                carve(len / 2, count - len / 2, min_block_size, pieces);
+            }
+        }
+
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+
+        // find where the rounded block would go, then free it again so it can be split up
+        let Some(index) = buddy::alloc(&mut self.blocks, self.root, best_size) else {
+            return self.record_attempt(None);
+        };
+        self.blocks[index].state = BlockState::Available;
+        let mut start = self.blocks[index].range.start;
+
+        let mut pieces = Vec::new();
+        carve(best_size, count, self.min_block_size, &mut pieces);
+
+        let mut allocations: Vec<Allocation> = Vec::with_capacity(pieces.len());
+        for (size, count) in pieces {
+            // each piece is aligned to its size, so the one block containing `start` is it
+            let piece = self.find_block(size, count, &|range| range.contains(&start));
+
+            let Some(piece) = piece else {
+                for allocation in allocations {
+                    let index = allocation.disown().unwrap();
+                    self.dealloc(index);
+                }
+
+                return self.record_attempt(None);
+            };
+
+            start += size;
+            allocations.push(piece);
+        }
+
+        self.record_attempt(Some(allocations))
+    }
+
+    /// Like `alloc`, but attaches `meta` to the allocation until it's freed
+    pub fn alloc_with_meta<M: Any + Send + Sync>(
+        &mut self,
+        count: usize,
+        meta: M,
+    ) -> Option<Allocation> {
+        let allocation = self.alloc(count)?;
+        let index = self.block_index(allocation.index).unwrap();
+        self.meta.insert(index, Arc::new(meta));
+
+        Some(allocation)
+    }
+
+    /// The data attached to `allocation` by `alloc_with_meta`, if it's an `M`
+    pub fn meta<M: Any>(&self, allocation: &Allocation) -> Option<&M> {
+        let index = self.block_index(allocation.index)?;
+        let block = self.blocks.get(index)?;
+        if block.serial != allocation.serial {
+            return None;
+        }
+
+        self.meta.get(&index)?.downcast_ref()
+    }
+
+    pub fn reserve(&mut self, count: usize) -> Option<Reservation> {
+        self.alloc(count).map(Reservation)
+    }
+
+    /// Allocates from the lowest free offset, for use as a stack. Allocations made this way
+    /// should be freed in reverse order with `free_stack`.
+    pub fn alloc_stack(&mut self, count: usize) -> Option<Allocation> {
+        let Some(best_size) = self.rounded_size_for(count) else {
+            return self.record_too_large();
+        };
+
+        // recycled blocks could be anywhere, so go straight to the tree, whose search is
+        // leftmost-first
+        let allocation = match self.can_hand_out(best_size) {
+            true => self.search_tree(best_size, count, &|_| true),
+            false => None,
+        };
+        let allocation = self.record_attempt(allocation)?;
+        self.stack.push(self.block_index(allocation.index).unwrap());
+
+        Some(allocation)
+    }
+
+    /// A raw handle for every occupied block, for taking over blocks whose `Allocation`s were
+    /// lost (say, with `mem::forget`). Any original handles that are still around must not be
+    /// used or dropped afterwards, or the block could be freed twice.
+    pub fn reconstruct_handles(&self) -> Vec<RawAllocation> {
+        self.owned_blocks()
+            .map(|(index, block)| RawAllocation {
+                index: self.handle_index(index),
+                range: block.range.start..(block.range.start + block.requested),
+            })
+            .collect()
+    }
+
+    /// Occupied blocks that belong to an allocation, rather than sitting in a recycle list
+    fn owned_blocks(&self) -> impl Iterator<Item = (Index, &Block)> + '_ {
+        let recycled: Vec<Index> = self
+            .recycle
+            .iter()
+            .flat_map(|recycle| recycle.blocks.iter().copied())
+            .collect();
+
+        self.blocks.iter().filter(move |(index, block)| {
+            matches!(block.state, BlockState::Occupied)
+                && !recycled.contains(index)
+                && block.range.start < self.region_len
+        })
+    }
+
+    /// The epoch the next allocation will be tagged with. Every allocation gets the next one
+    /// in order.
+    pub fn epoch(&self) -> u64 {
+        self.next_serial
+    }
+
+    /// The range of the live allocation with the earliest epoch, as of the last tidy
+    pub fn oldest_occupied(&self) -> Option<Range<usize>> {
+        self.owned_blocks()
+            .min_by_key(|(_, block)| block.serial)
+            .map(|(_, block)| block.range.start..(block.range.start + block.requested))
+    }
+
+    /// Frees every allocation made before `epoch`, whether or not its handle is still around,
+    /// and returns how many there were. Handles to them stop viewing anything, and dropping
+    /// them later does nothing.
+    pub fn free_older_than(&mut self, epoch: u64) -> usize {
+        let old: Vec<Index> = self
+            .owned_blocks()
+            .filter(|(_, block)| block.serial < epoch)
+            .map(|(index, _)| index)
+            .collect();
+
+        for &index in &old {
+            self.live_allocations -= 1;
+            self.meta.remove(&index);
+            self.release(index);
+
+            // the handle still has the old index, so don't let a new allocation reuse it
+            let path = self.block_path(index).unwrap();
+            let block = self.blocks.remove(index).unwrap();
+            let new_index = self.blocks.insert(block);
+
+            match path.len().checked_sub(2).map(|parent| path[parent]) {
+                Some(parent) => {
+                    if let BlockState::Split(first, second) = &mut self.blocks[parent].state {
+                        for child in [first, second] {
+                            if *child == index {
+                                *child = new_index;
+                            }
+                        }
+                    }
+                }
+                None => self.root = new_index,
+            }
+        }
+
+        self.stack.retain(|index| !old.contains(index));
+        self.tidy_cursor.clear();
+
+        old.len()
+    }
+
+    /// Frees a block taken over with `reconstruct_handles`, on the next tidy
+    pub fn free_raw(&mut self, raw: RawAllocation) {
+        self.received.get_mut().push(raw.index);
+    }
+
+    /// The sending end of the free queue, for funnelling frees through a collector of your
+    /// own. `None` for bookkeepings that don't use a channel.
+    pub fn reclaim_sender(&self) -> Option<mpsc::Sender<Index>> {
+        match &self.to_remove_sender {
+            Reclaim::Channel(sender) => Some(sender.clone()),
+            _ => None,
+        }
+    }
+
+    /// Frees blocks that were collected somewhere else, like through `reclaim_sender`, right
+    /// away. Indices that aren't occupied blocks of this bookkeeping are ignored.
+    pub fn reclaim_from(&mut self, indices: &[Index]) {
+        for &index in indices {
+            self.dealloc(index);
+        }
+    }
+
+    /// Which bookkeeping of its `ReclaimHub` this is, if it was made by one
+    pub fn arena_id(&self) -> Option<usize> {
+        match &self.to_remove_sender {
+            Reclaim::Tagged(_, arena) => Some(*arena),
+            _ => None,
+        }
+    }
+
+    /// Starts a scope whose allocations are all freed when it's dropped, like a bump
+    /// allocator that's reset every frame
+    pub fn scope(&mut self) -> Scope<'_> {
+        Scope {
+            bookkeeping: self,
+            indices: Vec::new(),
+        }
+    }
+
+    /// Frees an allocation from `alloc_stack` right away, merging its buddies back together
+    /// without waiting for a tidy. Debug builds check that it was the most recent one; release
+    /// builds still free it properly, but the buddies above it may not merge until a tidy.
+    pub fn free_stack(&mut self, allocation: Allocation) {
+        let block = self.block_index(allocation.index);
+
+        debug_assert_eq!(
+            self.stack.last().copied(),
+            block,
+            "stack allocations must be freed in reverse order"
+        );
+
+        let Some(handle) = allocation.disown() else {
+            return;
+        };
+
+        let Some(path) = block.and_then(|index| self.block_path(index)) else {
+            return;
+        };
+
+        // this takes it off the stack too
+        self.dealloc(handle);
+        self.merge_up(&path);
+    }
+
+    /// Merges the parents along `path` to a newly freed block, from the bottom up, until one
+    /// can't be merged. Parents that were already merged away are skipped.
+    fn merge_up(&mut self, path: &[Index]) {
+        for &parent in path.iter().rev().skip(1) {
+            if !self.blocks.contains(parent) {
+                continue;
+            }
+
+            if !buddy::merge(&mut self.blocks, parent) {
+                break;
+            }
+        }
+    }
+
+    /// Whether allocating all of `sizes` in order would succeed right now, without actually
+    /// allocating anything
+    pub fn plan(&self, sizes: &[usize]) -> bool {
+        if self
+            .max_allocations
+            .is_some_and(|max| self.live_allocations + sizes.len() > max)
+        {
+            return false;
+        }
+
+        let mut scratch = self.blocks.clone();
+        let mut recycled: Vec<usize> = self.recycle.iter().map(|r| r.blocks.len()).collect();
+
+        sizes.iter().all(|&count| {
+            let Some(best_size) = self.rounded_size_for(count) else {
+                return false;
+            };
+            if !self.allows_block(best_size) {
+                return false;
+            }
+
+            match recycled.get_mut(self.recycle_slot(best_size)) {
+                Some(available) if *available > 0 => {
+                    *available -= 1;
+
+                    true
+                }
+                _ => buddy::alloc(&mut scratch, self.root, best_size).is_some(),
+            }
+        })
+    }
+
+    /// Like `alloc`, but when there isn't room yet, returns a request that can be retried later
+    pub fn alloc_pending(&mut self, count: usize) -> Result<Allocation, PendingAlloc> {
+        self.alloc(count).ok_or(PendingAlloc { count })
+    }
+
+    pub fn poll_pending(&mut self, pending: &mut PendingAlloc) -> Option<Allocation> {
+        self.alloc(pending.count)
+    }
+
+    /// Like `alloc`, but if it fails, tidies and retries once
+    pub fn alloc_or_reuse(&mut self, count: usize) -> Option<Allocation> {
+        self.alloc(count).or_else(|| {
+            self.tidy();
+            self.alloc(count)
+        })
+    }
+
+    fn dealloc_pending_gas(&mut self, gas: &mut usize) {
+        // check the gas before receiving, so a free is never dropped on the floor
+        while *gas > 0 {
+            let Some(index) = self.next_pending() else {
+                break;
+            };
+
+            self.dealloc(index);
+
+            *gas -= 1;
+        }
+    }
+
+    fn next_pending(&mut self) -> Option<Index> {
+        self.received
+            .get_mut()
+            .pop()
+            .or_else(|| self.to_remove_receiver.try_recv())
+    }
+
+    fn is_occupied(&self, index: Index) -> bool {
+        matches!(
+            self.blocks.get(index),
+            Some(Block {
+                state: BlockState::Occupied,
+                ..
+            })
+        )
+    }
+
+    /// Frees the block a handle's index refers to
+    fn dealloc(&mut self, handle: Index) {
+        // allocations from before a reset refer to blocks that no longer exist
+        let Some(index) = self
+            .block_index(handle)
+            .filter(|&index| self.is_occupied(index))
+        else {
+            return;
+        };
+
+        self.live_allocations -= 1;
+        self.meta.remove(&index);
+        if let Some(position) = self.stack.iter().rposition(|&stacked| stacked == index) {
+            self.stack.remove(position);
+        }
+
+        let slot = self.recycle_slot(self.blocks[index].range.len());
+        if let Some(recycle) = self.recycle.get_mut(slot) {
+            if recycle.blocks.len() < recycle.capacity {
+                recycle.blocks.push(index);
+                // the block stays occupied, so give it a serial no handle has, or the one that
+                // was just freed would still pass as live
+                self.blocks[index].serial = u64::MAX;
+
+                return;
+            }
+        }
+
+        self.release(index);
+
+        if self.blocks[index].range.len() >= self.eager_coalesce_above {
+            if let Some(path) = self.block_path(index) {
+                self.merge_up(&path);
+            }
+        }
+    }
+
+    /// Returns an occupied block to the tree
+    fn release(&mut self, index: Index) {
+        buddy::dealloc(&mut self.blocks, index);
+
+        self.used_bytes -= self.blocks[index].range.len();
+
+        let usage = self.usage();
+        if let Some(pressure) = &mut self.pressure {
+            if usage <= pressure.threshold {
+                pressure.above = false;
+            }
+        }
+    }
+
+    fn recycle_slot(&self, block_size: usize) -> usize {
+        (block_size.ilog2() - self.min_block_size.ilog2()) as usize
+    }
+
+    /// Keeps up to `capacity` freed blocks of `block_size` aside instead of returning them to
+    /// the tree, so they can be reused by `alloc` without splitting or merging anything.
+    /// Blocks that are still unused by the next full `tidy` go back to the tree.
+    pub fn set_recycle_capacity(&mut self, block_size: usize, capacity: usize) {
+        assert!(self.size_classes().any(|size| size == block_size));
+
+        let slot = self.recycle_slot(block_size);
+        if self.recycle.len() <= slot {
+            self.recycle.resize_with(slot + 1, Recycle::default);
+        }
+
+        self.recycle[slot].capacity = capacity;
+
+        let excess = self.recycle[slot].blocks.len().saturating_sub(capacity);
+        for index in self.recycle[slot]
+            .blocks
+            .drain(..excess)
+            .collect::<Vec<_>>()
+        {
+            self.release(index);
+        }
+    }
+
+    fn flush_recycled(&mut self) {
+        for slot in 0..self.recycle.len() {
+            for index in std::mem::take(&mut self.recycle[slot].blocks) {
+                self.release(index);
+            }
+        }
+    }
+
+    /// Frees everything, going back to a single available block. Allocations from before the
+    /// reset stay usable as handles, but they no longer own anything, and dropping them is a
+    /// no-op.
+    pub fn reset(&mut self) {
+        self.collapse();
+
+        while self.next_pending().is_some() {}
+    }
+
+    /// Throws away the whole tree, occupied blocks and all, leaving a single available root.
+    /// Unlike `tidy`, this doesn't care what's still in use, and unlike `reset`, frees that
+    /// are still queued up aren't drained; they're ignored whenever they do get taken off the
+    /// queue, like any other handle from before the collapse.
+    pub fn collapse(&mut self) {
+        let size = self.blocks[self.root].range.len();
+
+        // clearing bumps the generation, so old indices can never match a new block
+        self.blocks.clear();
+        self.root = self.blocks.insert(Block::available(0..size));
+
+        self.used_bytes = 0;
+        self.occupy_tail();
+        self.live_allocations = 0;
+        self.stack.clear();
+        self.tidy_cursor.clear();
+        self.meta.clear();
+
+        for recycle in &mut self.recycle {
+            recycle.blocks.clear();
+        }
+
+        if let Some(pressure) = &mut self.pressure {
+            pressure.above = false;
+        }
+    }
+
+    /// Copies the current tree into a snapshot that can be inspected but not changed.
+    /// Allocations from this bookkeeping have no effect on it.
+    pub fn freeze(&self) -> FrozenBuddyBookkeeping {
+        let mut frozen = BuddyBookkeeping::new(
+            self.blocks[self.root].range.len(),
+            self.min_block_size,
+            self.max_block_size,
+        );
+
+        // frees still on the channel count towards queries like `max_coalescible_free`
+        let mut received = self.received.borrow_mut();
+        received.extend(std::iter::from_fn(|| self.to_remove_receiver.try_recv()));
+        *frozen.received.get_mut() = received.clone();
+        drop(received);
+
+        frozen.blocks = self.blocks.clone();
+        frozen.root = self.root;
+        frozen.generation_offset = self.generation_offset;
+        frozen.used_bytes = self.used_bytes;
+        frozen.next_serial = self.next_serial;
+        frozen.live_allocations = self.live_allocations;
+        frozen.max_allocations = self.max_allocations;
+        frozen.reject_max_class = self.reject_max_class;
+        frozen.frozen_classes = self.frozen_classes;
+        #[cfg(feature = "test-util")]
+        {
+            frozen.fail_after = self.fail_after;
+        }
+        frozen.latencies = self.latencies.clone();
+        frozen.recycle = self.recycle.clone();
+        frozen.stack = self.stack.clone();
+        frozen.meta = self.meta.clone();
+        frozen.base_offset = self.base_offset;
+        frozen.region_len = self.region_len;
+        frozen.stats = self.stats;
+        frozen.eager_coalesce_above = self.eager_coalesce_above;
+        frozen.base_alignment = self.base_alignment;
+
+        FrozenBuddyBookkeeping(frozen)
+    }
+
+    /// Saves the current state of the tree, so it can be restored with `rewind`
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            blocks: self.blocks.clone(),
+            root: self.root,
+            generation_offset: self.generation_offset,
+            used_bytes: self.used_bytes,
+            live_allocations: self.live_allocations,
+            recycle: self.recycle.clone(),
+            stack: self.stack.clone(),
+        }
+    }
+
+    /// Puts the tree back the way it was at `checkpoint`, which must have come from this
+    /// bookkeeping. Everything allocated since is free again, and handles to those
+    /// allocations are ignored when they're dropped. Blocks freed since the checkpoint stay
+    /// free.
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        /// Copies the checkpoint's tree under `index` back into `blocks`. Blocks that are still
+        /// held keep their index for the handles. Everything else gets an index nothing has
+        /// seen yet, since allocations made since the checkpoint may be holding the old ones.
+        fn rebuild(
+            saved: &Arena<Block>,
+            blocks: &mut Arena<Block>,
+            index: Index,
+            kept: &HashSet<Index>,
+            recycled: &mut HashMap<Index, Index>,
+            region_len: usize,
+        ) -> Index {
+            let block = &saved[index];
+
+            let state = match block.state {
+                BlockState::Occupied if kept.contains(&index) => return index,
+                BlockState::Occupied if recycled.contains_key(&index) => BlockState::Occupied,
+                // past the end of a region, which is never freed
+                BlockState::Occupied if block.range.start >= region_len => BlockState::Occupied,
+                // freed since the checkpoint
+                BlockState::Occupied => BlockState::Available,
+                BlockState::Split(first, second) => BlockState::Split(
+                    rebuild(saved, blocks, first, kept, recycled, region_len),
+                    rebuild(saved, blocks, second, kept, recycled, region_len),
+                ),
+                BlockState::Available => BlockState::Available,
+            };
+
+            let new_index = blocks.insert(Block {
+                state,
+                ..block.clone()
+            });
+            if let Some(moved) = recycled.get_mut(&index) {
+                *moved = new_index;
+            }
+
+            new_index
+        }
+
+        // recycled blocks at the checkpoint, to be given their new index by `rebuild`
+        let mut recycled: HashMap<Index, Index> = checkpoint
+            .recycle
+            .iter()
+            .flat_map(|recycle| recycle.blocks.iter().map(|&index| (index, index)))
+            .collect();
+
+        // blocks occupied at the checkpoint that still belong to the same allocation, or are
+        // still recycled. A `shrink_bookkeeping` since means nothing was held across it.
+        let same_tree = checkpoint.generation_offset == self.generation_offset;
+        let kept: HashSet<Index> = checkpoint
+            .blocks
+            .iter()
+            .filter(|&(index, block)| {
+                let still_held = self.blocks.get(index).is_some_and(|now| {
+                    matches!(now.state, BlockState::Occupied) && now.serial == block.serial
+                });
+
+                same_tree && matches!(block.state, BlockState::Occupied) && still_held
+            })
+            .map(|(index, _)| index)
+            .collect();
+        recycled.retain(|index, _| !kept.contains(index));
+
+        let (mut freed, mut freed_bytes) = (0, 0);
+        for (index, block) in checkpoint.blocks.iter() {
+            let occupied = matches!(block.state, BlockState::Occupied);
+            let tail = block.range.start >= self.region_len;
+            if occupied && !tail && !kept.contains(&index) && !recycled.contains_key(&index) {
+                freed += 1;
+                freed_bytes += block.range.len();
+            }
+        }
+
+        // everything but the blocks that are kept is rebuilt, in the same arena so the
+        // indices it's handed out so far stay invalid
+        self.blocks.retain(|index, _| kept.contains(&index));
+        self.root = rebuild(
+            &checkpoint.blocks,
+            &mut self.blocks,
+            checkpoint.root,
+            &kept,
+            &mut recycled,
+            self.region_len,
+        );
+
+        self.used_bytes = checkpoint.used_bytes - freed_bytes;
+        self.live_allocations = checkpoint.live_allocations - freed;
+        self.recycle = checkpoint.recycle;
+        for recycle in &mut self.recycle {
+            for index in &mut recycle.blocks {
+                if let Some(&moved) = recycled.get(index) {
+                    *index = moved;
+                }
+            }
+        }
+        self.stack = checkpoint.stack;
+        self.stack.retain(|index| kept.contains(index));
+        self.tidy_cursor.clear();
+
+        let blocks = &self.blocks;
+        self.meta.retain(|&index, _| {
+            blocks
+                .get(index)
+                .is_some_and(|block| matches!(block.state, BlockState::Occupied))
+        });
+    }
+
+    pub fn tidy(&mut self) {
+        self.tidy_with(|_| {});
+    }
+
+    /// Same as `tidy`, but calls `on_free` with the block range of every allocation it frees
+    pub(crate) fn tidy_with(&mut self, mut on_free: impl FnMut(Range<usize>)) {
+        let nodes_before = self.blocks.len();
+        self.flush_recycled();
+
+        while let Some(index) = self.next_pending() {
+            if let Some(block) = self.handle_block(index) {
+                if matches!(block.state, BlockState::Occupied) {
+                    on_free(block.range.clone());
+                }
+            }
+
+            self.dealloc(index);
+        }
+
+        buddy::tidy(&mut self.blocks, self.root);
+        self.record_tidy(nodes_before);
+    }
+
+    /// Same as `tidy`, but says whether it got anywhere: true if it freed an allocation or
+    /// merged any blocks. Retrying an allocation after a tidy that returns false is pointless.
+    pub fn tidy_progress(&mut self) -> bool {
+        let recycled =
+            |this: &Self| -> usize { this.recycle.iter().map(|slot| slot.blocks.len()).sum() };
+        let before = (self.blocks.len(), self.live_allocations, recycled(self));
+
+        self.tidy();
+
+        before != (self.blocks.len(), self.live_allocations, recycled(self))
+    }
+
+    pub fn tidy_gas(&mut self, gas: usize) {
+        self.tidy_gas_biased(gas, Bias::Left);
+    }
+
+    /// Like `tidy`, but only merges buddies when the merged block would be at least
+    /// `min_merge` big, leaving small free buddies split
+    pub fn tidy_min_size(&mut self, min_merge: usize) {
+        let nodes_before = self.blocks.len();
+        self.flush_recycled();
+
+        while let Some(index) = self.next_pending() {
+            self.dealloc(index);
+        }
+
+        buddy::tidy_min_size(&mut self.blocks, self.root, min_merge);
+        self.record_tidy(nodes_before);
+    }
+
+    /// Same as `tidy_gas`, which already coalesces the low end of the arena first
+    pub fn tidy_leftmost(&mut self, gas: usize) {
+        self.tidy_gas_biased(gas, Bias::Left);
+    }
+
+    /// Like `tidy_gas`, but coalesces the high end of the arena first, so if the gas runs out
+    /// the freed space collects there
+    pub fn tidy_rightmost(&mut self, gas: usize) {
+        self.tidy_gas_biased(gas, Bias::Right);
+    }
+
+    fn tidy_gas_biased(&mut self, gas: usize, bias: Bias) {
+        let nodes_before = self.blocks.len();
+        let mut gas = gas;
+        self.dealloc_pending_gas(&mut gas);
+
+        buddy::tidy_gas_biased(&mut self.blocks, self.root, &mut gas, bias);
+        self.record_tidy(nodes_before);
+    }
+
+    /// Like `tidy_gas`, but picks up where the last call left off instead of starting from
+    /// the root, so repeated small calls eventually cover the whole tree. Once a pass
+    /// finishes, the next call starts again from the root.
+    pub fn tidy_step(&mut self, gas: usize) {
+        let nodes_before = self.blocks.len();
+        let mut gas = gas;
+        self.dealloc_pending_gas(&mut gas);
+
+        if self.tidy_cursor.is_empty() {
+            self.tidy_cursor.push((self.root, false));
+        }
+
+        while gas > 0 {
+            let Some((index, children_visited)) = self.tidy_cursor.pop() else {
+                break;
+            };
+
+            // the tree may have changed since the cursor was saved
+            let Some(block) = self.blocks.get(index) else {
+                continue;
+            };
+
+            gas -= 1;
+
+            if let BlockState::Split(first, second) = block.state {
+                if children_visited {
+                    buddy::merge(&mut self.blocks, index);
+                } else {
+                    self.tidy_cursor.push((index, true));
+                    self.tidy_cursor.push((second, false));
+                    self.tidy_cursor.push((first, false));
+                }
+            }
+        }
+
+        self.record_tidy(nodes_before);
+    }
+
+    pub fn tidy_timed(&mut self, deadline: Instant) {
+        let nodes_before = self.blocks.len();
+
+        // check the deadline before receiving, so a free is never dropped on the floor
+        while Instant::now() < deadline {
+            let Some(index) = self.next_pending() else {
+                break;
+            };
+
+            self.dealloc(index);
+        }
+
+        buddy::tidy_timed(&mut self.blocks, self.root, deadline);
+        self.record_tidy(nodes_before);
+    }
+
+    /// Flat pre-order listing of every block as `(depth, range, state)`, where the state is
+    /// 0 for available, 1 for occupied, and 2 for split.
+    pub fn snapshot(&self) -> Vec<(usize, Range<usize>, u8)> {
+        let mut snapshot = Vec::with_capacity(self.blocks.len());
+        let mut stack = vec![(0, self.root)];
+
+        while let Some((depth, index)) = stack.pop() {
+            let block = &self.blocks[index];
+
+            let state = match block.state {
+                BlockState::Available => 0,
+                BlockState::Occupied => 1,
+                BlockState::Split(first, second) => {
+                    // push second first so first gets visited first
+                    stack.push((depth + 1, second));
+                    stack.push((depth + 1, first));
+
+                    2
+                }
+            };
+
+            snapshot.push((depth, block.range.clone(), state));
+        }
+
+        snapshot
+    }
+
+    /// A hash of the tree's shape and block states that stays the same across runs, builds,
+    /// and platforms (FNV-1a over `snapshot`). Allocation is deterministic, so the same
+    /// sequence of operations always ends up with the same hash.
+    pub fn state_hash(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+
+        let mut hash = OFFSET_BASIS;
+        let mut write = |x: u64| {
+            for byte in x.to_le_bytes() {
+                hash = (hash ^ u64::from(byte)).wrapping_mul(PRIME);
+            }
+        };
+
+        for (depth, range, state) in self.snapshot() {
+            write(depth as u64);
+            write(range.start as u64);
+            write(range.end as u64);
+            write(u64::from(state));
+        }
+
+        hash
+    }
+}
+
+/// Two bookkeepings are equal when their trees have the same shape and block states, however
+/// they got there. Queued frees, recycled blocks, and counters aren't compared.
+impl PartialEq for BuddyBookkeeping {
+    fn eq(&self, other: &BuddyBookkeeping) -> bool {
+        self.snapshot() == other.snapshot()
+    }
+}
+
+impl Eq for BuddyBookkeeping {}
+
+/// Puts an element back into a clean state, for `tidy_zeroize` and `alloc_zeroed`. Anything
+/// with a `Default` resets to it.
+pub trait Resettable {
+    fn reset(&mut self);
+}
+
+impl<T: Default> Resettable for T {
+    fn reset(&mut self) {
+        *self = T::default();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewError {
+    /// The allocation's range doesn't fit inside the element buffer
+    OutOfBounds,
+    /// The allocation's block is no longer occupied in this arena, or has been handed out again
+    StaleIndex,
+}
+
+/// From `BuddyArena::check_alignment_for`, when allocations can't be relied on to be aligned
+/// enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MisalignedError {
+    pub required: usize,
+    /// The alignment every allocation's first element is known to have, in bytes
+    pub guaranteed: usize,
+}
+
+/// Checks that byte offsets that are all multiples of `offsets`' lowest set bit are aligned
+/// well enough for a `U`
+fn check_alignment<U>(offsets: usize) -> Result<(), MisalignedError> {
+    let guaranteed = 1 << offsets.trailing_zeros();
+
+    let required = std::mem::align_of::<U>();
+    if guaranteed < required {
+        return Err(MisalignedError {
+            required,
+            guaranteed,
+        });
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryNewError {
+    /// The sizes aren't powers of two, or aren't in the order `min <= max <= size`
+    InvalidSizes,
+    /// The element buffer couldn't be allocated
+    OutOfMemory(TryReserveError),
+}
+
+/// Dropping the arena drops every element exactly once, whether or not it's allocated, and
+/// then the bookkeeping. Allocations can safely outlive the arena; dropping them afterwards
+/// does nothing.
+pub struct BuddyArena<T> {
+    // fields drop in order, so the elements go first
+    elements: Box<[T]>,
+    bookkeeping: BuddyBookkeeping,
+    /// Whether views check that the allocation still owns its block
+    strict_views: bool,
+}
+
+impl<T> BuddyArena<T> {
+    pub fn new(size: usize, min_block_size: usize, max_block_size: usize) -> BuddyArena<T>
+    where
+        T: Default,
+    {
+        let elements_vec: Vec<T> = repeat_with(|| T::default()).take(size).collect();
+
+        BuddyArena {
+            elements: elements_vec.into(),
+            bookkeeping: BuddyBookkeeping::new(size, min_block_size, max_block_size),
+            strict_views: cfg!(debug_assertions),
+        }
+    }
+
+    /// Like `new`, but returns an error instead of panicking on bad sizes or aborting when
+    /// the element buffer can't be allocated
+    pub fn try_new(
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> Result<BuddyArena<T>, TryNewError>
+    where
+        T: Default,
+    {
+        let sizes_ok = is_pow_of_two(size)
+            && is_pow_of_two(min_block_size)
+            && is_pow_of_two(max_block_size)
+            && max_block_size <= size
+            && min_block_size <= max_block_size;
+        if !sizes_ok {
+            return Err(TryNewError::InvalidSizes);
+        }
+
+        let mut elements_vec: Vec<T> = Vec::new();
+        elements_vec
+            .try_reserve_exact(size)
+            .map_err(TryNewError::OutOfMemory)?;
+        elements_vec.extend(repeat_with(|| T::default()).take(size));
+
+        Ok(BuddyArena {
+            elements: elements_vec.into(),
+            bookkeeping: BuddyBookkeeping::new(size, min_block_size, max_block_size),
+            strict_views: cfg!(debug_assertions),
+        })
+    }
+
+    pub fn bookkeeping(&self) -> &BuddyBookkeeping {
+        &self.bookkeeping
+    }
+
+    /// For the settings and operations that aren't forwarded here, like
+    /// `BuddyBookkeeping::set_recycle_capacity` or `checkpoint`/`rewind`
+    pub fn bookkeeping_mut(&mut self) -> &mut BuddyBookkeeping {
+        &mut self.bookkeeping
+    }
+
+    /// Makes `view` and friends panic when given an allocation that no longer owns its block
+    /// (say, one from before a reset). This is on by default in debug builds.
+    pub fn set_strict_views(&mut self, strict: bool) {
+        self.strict_views = strict;
+    }
+
+    fn check_live(&self, a: &Allocation) {
+        if self.strict_views {
+            let live = match self.bookkeeping.handle_block(a.index) {
+                Some(block) => {
+                    matches!(block.state, BlockState::Occupied) && block.serial == a.serial
+                }
+                None => false,
+            };
+
+            assert!(
+                live,
+                "viewed an allocation that has been freed: {:?}",
+                a.range
+            );
+        }
+    }
+
+    fn debug_check_bounds(&self, a: &Allocation) {
+        debug_assert!(
+            a.range.end <= self.elements.len(),
+            "allocation range {:?} goes past the end of the {} elements",
+            a.range,
+            self.elements.len()
+        );
+    }
+
+    /// Checks that every occupied block lies within the element buffer
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.elements.len();
+
+        for (_, block) in self.bookkeeping.owned_blocks() {
+            if block.range.end > len {
+                return Err(format!(
+                    "occupied block {:?} goes past the end of the {len} elements",
+                    block.range
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn view(&self, a: &Allocation) -> &[T] {
+        self.check_live(a);
+        self.debug_check_bounds(a);
+
+        &self.elements[a.range.clone()]
+    }
+
+    /// Like `view`, but debug builds always check that `a` is the current handle for its
+    /// block, even without strict views, catching handles from before a `realloc`
+    pub fn view_checked(&self, a: &Allocation) -> &[T] {
+        debug_assert!(
+            self.bookkeeping.handle_block(a.index).is_some_and(|block| {
+                matches!(block.state, BlockState::Occupied) && block.serial == a.serial
+            }),
+            "allocation is stale, it was reallocated or freed since it was handed out"
+        );
+
+        &self.elements[a.range.clone()]
+    }
+
+    pub fn view_mut(&mut self, a: &Allocation) -> &mut [T] {
+        self.check_live(a);
+        self.debug_check_bounds(a);
+
+        &mut self.elements[a.range.clone()]
+    }
+
+    /// Hints to the CPU that `a` is about to be read, so it starts pulling it into cache. Only
+    /// does anything on x86_64; elsewhere it's a no-op.
+    pub fn prefetch(&self, a: &Allocation) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+            const CACHE_LINE: usize = 64;
+
+            let elements = &self.elements[a.range.clone()];
+            let start = elements.as_ptr().cast::<i8>();
+
+            for offset in (0..std::mem::size_of_val(elements)).step_by(CACHE_LINE) {
+                // SAFETY: prefetching is only a hint and never faults, and the pointer stays
+                // inside the slice anyway
+                unsafe { _mm_prefetch(start.add(offset), _MM_HINT_T0) };
+            }
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = &self.elements[a.range.clone()];
+    }
+
+    /// Views an allocation as its raw bytes. Bytes can start anywhere, so unlike
+    /// `transmute_elements` this never needs `check_alignment_for`.
+    #[cfg(feature = "bytemuck")]
+    pub fn view_bytes(&self, a: &Allocation) -> &[u8]
+    where
+        T: bytemuck::Pod,
+    {
+        bytemuck::cast_slice(self.view(a))
+    }
+
+    #[cfg(feature = "bytemuck")]
+    pub fn view_bytes_mut(&mut self, a: &Allocation) -> &mut [u8]
+    where
+        T: bytemuck::Pod,
+    {
+        bytemuck::cast_slice_mut(self.view_mut(a))
+    }
+
+    /// Checks that the first element of every allocation is aligned well enough to be read
+    /// as a `U`, which needs the smallest block, in bytes, and the buffer itself to both be
+    /// aligned to `U`. Pieces from `Allocation::split_at` can start anywhere, so this doesn't
+    /// cover them.
+    pub fn check_alignment_for<U>(&self) -> Result<(), MisalignedError> {
+        let address = self.elements.as_ptr() as usize;
+
+        check_alignment::<U>(self.block_stride() | address)
+    }
+
+    /// The size of the smallest block, in bytes. Every block starts at a multiple of this.
+    fn block_stride(&self) -> usize {
+        self.bookkeeping.min_block_size * std::mem::size_of::<T>()
+    }
+
+    /// Views an allocation of exactly `N` elements as an array
+    pub fn view_array<const N: usize>(&self, a: &Allocation) -> Option<&[T; N]> {
+        self.view(a).try_into().ok()
+    }
+
+    pub fn view_array_mut<const N: usize>(&mut self, a: &Allocation) -> Option<&mut [T; N]> {
+        self.view_mut(a).try_into().ok()
+    }
+
+    pub fn view_shared(&self, s: &SharedAllocation) -> &[T] {
+        self.view(&s.0)
+    }
+
+    /// Views a weak allocation, if the allocation it came from hasn't been freed yet
+    pub fn upgrade_view(&self, weak: &WeakAllocation) -> Option<&[T]> {
+        match self.bookkeeping.handle_block(weak.index) {
+            Some(Block {
+                state: BlockState::Occupied,
+                serial,
+                ..
+            }) if *serial == weak.serial => Some(&self.elements[weak.range.clone()]),
+            _ => None,
+        }
+    }
+
+    /// Views part of an allocation, with `sub` relative to the start of the allocation
+    pub fn view_range(&self, a: &Allocation, sub: Range<usize>) -> Option<&[T]> {
+        self.view(a).get(sub)
+    }
+
+    pub fn view_range_mut(&mut self, a: &Allocation, sub: Range<usize>) -> Option<&mut [T]> {
+        self.view_mut(a).get_mut(sub)
+    }
+
+    pub fn try_view(&self, a: &Allocation) -> Result<&[T], ViewError> {
+        self.check_view(a)?;
+
+        Ok(&self.elements[a.range.clone()])
+    }
+
+    pub fn try_view_mut(&mut self, a: &Allocation) -> Result<&mut [T], ViewError> {
+        self.check_view(a)?;
+
+        Ok(&mut self.elements[a.range.clone()])
+    }
+
+    /// Views several allocations mutably at once. Returns `None` if any of their ranges
+    /// overlap.
+    pub fn views_mut<'a>(&'a mut self, allocs: &[&Allocation]) -> Option<Vec<&'a mut [T]>> {
+        for a in allocs {
+            self.check_live(a);
+        }
+
+        let mut order: Vec<usize> = (0..allocs.len()).collect();
+        order.sort_by_key(|&i| allocs[i].range.start);
+
+        if order
+            .windows(2)
+            .any(|pair| allocs[pair[0]].range.end > allocs[pair[1]].range.start)
+        {
+            return None;
+        }
+
+        let mut views: Vec<Option<&'a mut [T]>> = repeat_with(|| None).take(allocs.len()).collect();
+        let mut rest = &mut self.elements[..];
+        let mut offset = 0;
+
+        // walk the buffer in address order, peeling off each view
+        for i in order {
+            let range = allocs[i].range.clone();
+
+            let (_, tail) = std::mem::take(&mut rest).split_at_mut(range.start - offset);
+            let (view, tail) = tail.split_at_mut(range.len());
+
+            views[i] = Some(view);
+            rest = tail;
+            offset = range.end;
+        }
+
+        Some(views.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Copies each allocation's contents into `out` back to back, returning how many elements
+    /// were written, or `None` (without writing anything) if `out` is too small
+    pub fn gather_into(&self, allocs: &[&Allocation], out: &mut [T]) -> Option<usize>
+    where
+        T: Copy,
+    {
+        let total: usize = allocs.iter().map(|a| a.range.len()).sum();
+        if total > out.len() {
+            return None;
+        }
+
+        let mut offset = 0;
+        for a in allocs {
+            let view = self.view(a);
+
+            out[offset..offset + view.len()].copy_from_slice(view);
+            offset += view.len();
+        }
+
+        Some(total)
+    }
+
+    /// The reverse of `gather_into`: fills each allocation in turn from `input`, returning how
+    /// many elements were read, or `None` (without writing anything) if `input` is too short
+    pub fn scatter_from(&mut self, allocs: &[&Allocation], input: &[T]) -> Option<usize>
+    where
+        T: Copy,
+    {
+        let total: usize = allocs.iter().map(|a| a.range.len()).sum();
+        if total > input.len() {
+            return None;
+        }
+
+        let mut offset = 0;
+        for a in allocs {
+            let view = self.view_mut(a);
+            let len = view.len();
+
+            view.copy_from_slice(&input[offset..offset + len]);
+            offset += len;
+        }
+
+        Some(total)
+    }
+
+    fn check_view(&self, a: &Allocation) -> Result<(), ViewError> {
+        if a.range.start > a.range.end || a.range.end > self.elements.len() {
+            return Err(ViewError::OutOfBounds);
+        }
+
+        match self.bookkeeping.handle_block(a.index) {
+            Some(
+                block @ Block {
+                    state: BlockState::Occupied,
+                    ..
+                },
+            ) if block.serial == a.serial => Ok(()),
+            _ => Err(ViewError::StaleIndex),
+        }
+    }
+
+    pub fn alloc(&mut self, count: usize) -> Option<Allocation> {
+        self.bookkeeping.alloc(count)
+    }
+
+    pub fn alloc_exact(&mut self, size: usize) -> Option<Allocation> {
+        self.bookkeeping.alloc_exact(size)
+    }
+
+    pub fn alloc_aligned(&mut self, count: usize, align: usize) -> Option<Allocation> {
+        self.bookkeeping.alloc_aligned(count, align)
+    }
+
+    pub fn alloc_aligned_const<const ALIGN: usize>(
+        &mut self,
+        count: usize,
+    ) -> Option<AlignedAllocation<ALIGN>> {
+        self.bookkeeping.alloc_aligned_const(count)
+    }
+
+    pub fn alloc_or_reuse(&mut self, count: usize) -> Option<Allocation> {
+        self.bookkeeping.alloc_or_reuse(count)
+    }
+
+    pub fn alloc_with_meta<M: Any + Send + Sync>(
+        &mut self,
+        count: usize,
+        meta: M,
+    ) -> Option<Allocation> {
+        self.bookkeeping.alloc_with_meta(count, meta)
+    }
+
+    pub fn free_older_than(&mut self, epoch: u64) -> usize {
+        self.bookkeeping.free_older_than(epoch)
+    }
+
+    /// Moves an allocation's contents into `other`, freeing the original. If the allocation is
+    /// pinned or `other` doesn't have room, the original allocation is handed back untouched.
+    pub fn migrate(
+        &mut self,
+        other: &mut BuddyArena<T>,
+        alloc: Allocation,
+    ) -> Result<Allocation, Allocation>
+    where
+        T: Clone,
+    {
+        if alloc.is_pinned() {
+            return Err(alloc);
+        }
+
+        let Some(new_alloc) = other.alloc(alloc.range().len()) else {
+            return Err(alloc);
+        };
+
+        other
+            .view_mut(&new_alloc)
+            .clone_from_slice(self.view(&alloc));
+
+        Ok(new_alloc)
+    }
+
+    /// Resizes an allocation to `count` elements, keeping as many of its elements as fit. It
+    /// stays in its block if `count` fits there, and is moved otherwise, with the old block
+    /// freed on the next tidy. Either way the result has a new serial, so anything still
+    /// holding the old handle's identity is caught by `view_checked`. If there's no room, or
+    /// the allocation would have to move but is pinned or a piece of a split one, it's handed
+    /// back untouched.
+    pub fn realloc(&mut self, alloc: Allocation, count: usize) -> Result<Allocation, Allocation> {
+        let alloc = match self.bookkeeping.restamp(alloc, count) {
+            Ok(restamped) => return Ok(restamped),
+            Err(alloc) if alloc.shared.is_some() || alloc.pinned || count == 0 => {
+                return Err(alloc)
+            }
+            Err(alloc) => alloc,
+        };
+
+        let Some(new_alloc) = self.alloc(count) else {
+            return Err(alloc);
+        };
+
+        let mut views = self.views_mut(&[&alloc, &new_alloc]).unwrap();
+        let new_view = views.pop().unwrap();
+        let old_view = views.pop().unwrap();
+
+        let kept = old_view.len().min(new_view.len());
+        new_view[..kept].swap_with_slice(&mut old_view[..kept]);
+
+        Ok(new_alloc)
+    }
+
+    /// Packs pairs of same-sized allocations that would both fit in one of their blocks into
+    /// a single allocation, so the other block can be freed. The second one's elements are
+    /// moved to just after the first one's, and `relocate(old, new)` is called with where they
+    /// were and where they are now. Returns the allocations that are left, in the order they
+    /// were given, with each packed pair where the one that took in the other was; the emptied
+    /// ones are freed on the next tidy. Pairs are packed smallest block size first, and the
+    /// result is the same every time for the same input.
+    ///
+    /// Two allocations that were only rounded up to the next power of two never fit together,
+    /// so this only finds anything when blocks were made bigger than needed, by
+    /// `min_block_size` or an alignment. Two 24-element allocations in 32 blocks, for example,
+    /// can't be packed. Pinned allocations, and pieces of a split allocation, are left alone.
+    pub fn consolidate<F>(
+        &mut self,
+        allocations: Vec<Allocation>,
+        mut relocate: F,
+    ) -> Vec<Allocation>
+    where
+        F: FnMut(Range<usize>, Range<usize>),
+    {
+        // remember where each one was, to hand them back in the same order
+        let (candidates, mut kept): (Vec<_>, Vec<_>) = allocations
+            .into_iter()
+            .enumerate()
+            .partition(|(_, a)| !a.pinned && a.shared.is_none());
+
+        let mut by_size: BTreeMap<usize, Vec<(usize, Allocation)>> = BTreeMap::new();
+        for (position, a) in candidates {
+            self.check_live(&a);
+
+            let block_size = self.bookkeeping.handle_block(a.index).unwrap().range.len();
+            by_size.entry(block_size).or_default().push((position, a));
+        }
+
+        for (block_size, mut group) in by_size {
+            group.sort_by_key(|(_, a)| a.range.len());
+            let mut group: VecDeque<_> = group.into();
+
+            // pair the biggest with the smallest, dropping the biggest when nothing fits it
+            while group.len() >= 2 {
+                let (small, big) = (&group[0].1, &group[group.len() - 1].1);
+                if small.range.len() + big.range.len() > block_size {
+                    kept.push(group.pop_back().unwrap());
+                    continue;
+                }
+
+                let (position, mut big) = group.pop_back().unwrap();
+                let (_, small) = group.pop_front().unwrap();
+
+                let new_range = big.range.end..(big.range.end + small.range.len());
+                for (from, to) in small.range.clone().zip(new_range.clone()) {
+                    self.elements.swap(from, to);
+                }
+                relocate(
+                    small.range(),
+                    (big.base + new_range.start)..(big.base + new_range.end),
+                );
+
+                big.range.end = new_range.end;
+                let index = self.bookkeeping.block_index(big.index).unwrap();
+                self.bookkeeping.blocks[index].requested = big.range.len();
+                kept.push((position, big));
+            }
+
+            kept.extend(group);
+        }
+
+        kept.sort_by_key(|&(position, _)| position);
+        kept.into_iter().map(|(_, a)| a).collect()
+    }
+
+    /// Frees everything and sets every element back to `T::default()`
+    pub fn reset(&mut self)
+    where
+        T: Default,
+    {
+        self.bookkeeping.reset();
+        self.elements.fill_with(T::default);
+    }
+
+    /// Frees everything, but leaves the elements as they are, so reallocating a region sees
+    /// whatever was last written there
+    pub fn reset_keep_data(&mut self) {
+        self.bookkeeping.reset();
+    }
+
+    pub fn tidy(&mut self) {
+        self.bookkeeping.tidy();
+    }
+
+    pub fn tidy_progress(&mut self) -> bool {
+        self.bookkeeping.tidy_progress()
+    }
+
+    /// Like `tidy`, but also resets every element of the blocks it frees
+    pub fn tidy_zeroize(&mut self)
+    where
+        T: Resettable,
+    {
+        let elements = &mut self.elements;
+
+        self.bookkeeping.tidy_with(|range| {
+            for element in &mut elements[range] {
+                element.reset();
+            }
+        });
+    }
+
+    /// Like `alloc`, but resets every element of the allocation before handing it out
+    pub fn alloc_zeroed(&mut self, count: usize) -> Option<Allocation>
+    where
+        T: Resettable,
+    {
+        let allocation = self.alloc(count)?;
+
+        for element in &mut self.elements[allocation.range.clone()] {
+            element.reset();
+        }
+
+        Some(allocation)
+    }
+
+    /// Allocates the whole rounded-up block for `count` elements, resetting the padding after
+    /// the first `count`. The range covers the padding too, while `Allocation::logical_len`
+    /// is `count`.
+    pub fn alloc_padded(&mut self, count: usize) -> Option<Allocation>
+    where
+        T: Resettable,
+    {
+        let block_size = self.bookkeeping.rounded_size_for(count)?;
+
+        let mut allocation = self.bookkeeping.alloc(block_size)?;
+        let index = self.bookkeeping.block_index(allocation.index).unwrap();
+        self.bookkeeping.blocks[index].requested = count;
+        allocation.logical_len = Some(count);
+
+        let padding = (allocation.range.start + count)..allocation.range.end;
+        for element in &mut self.elements[padding] {
+            element.reset();
+        }
+
+        Some(allocation)
+    }
+
+    pub fn tidy_gas(&mut self, gas: usize) {
+        self.bookkeeping.tidy_gas(gas);
+    }
+
+    pub fn tidy_min_size(&mut self, min_merge: usize) {
+        self.bookkeeping.tidy_min_size(min_merge);
+    }
+
+    pub fn tidy_leftmost(&mut self, gas: usize) {
+        self.bookkeeping.tidy_leftmost(gas);
+    }
+
+    pub fn tidy_rightmost(&mut self, gas: usize) {
+        self.bookkeeping.tidy_rightmost(gas);
+    }
+
+    pub fn tidy_step(&mut self, gas: usize) {
+        self.bookkeeping.tidy_step(gas);
+    }
+
+    pub fn tidy_timed(&mut self, deadline: Instant) {
+        self.bookkeeping.tidy_timed(deadline);
+    }
+}
+
+impl<T: Copy> BuddyArena<T> {
+    /// Reinterprets the element buffer as `U`s, scaling every block (and the block size limits)
+    /// by the ratio of the element sizes. Returns `None` if any block, allocation, or size limit
+    /// doesn't land on a whole number of `U`s, wouldn't stay a power of two, or wouldn't start
+    /// aligned for a `U` (see `check_alignment_for`). Outstanding
+    /// allocations keep working, but their ranges need converting with
+    /// `Allocation::transmute_elements` before they're used to view the new arena.
+    ///
+    /// # Safety
+    ///
+    /// Every bit pattern that could be in the buffer must be a valid `U`.
+    pub unsafe fn transmute_elements<U: Copy>(self) -> Option<BuddyArena<U>> {
+        let (from, to) = (std::mem::size_of::<T>(), std::mem::size_of::<U>());
+        if from == 0 || to == 0 {
+            return None;
+        }
+
+        // the elements are copied into a new buffer that's aligned for `U`, so only where the
+        // blocks start matters
+        check_alignment::<U>(self.block_stride()).ok()?;
+
+        let BuddyArena {
+            elements,
+            mut bookkeeping,
+            strict_views,
+        } = self;
+
+        if !bookkeeping.rescale(from, to) {
+            return None;
+        }
+
+        let mut new_elements = Box::<[U]>::new_uninit_slice(elements.len() * from / to);
+
+        // SAFETY: both buffers are exactly `elements.len() * from` bytes long, and the caller
+        // guarantees those bytes are valid `U`s
+        let new_elements = unsafe {
+            std::ptr::copy_nonoverlapping(
+                elements.as_ptr() as *const u8,
+                new_elements.as_mut_ptr() as *mut u8,
+                elements.len() * from,
+            );
+
+            new_elements.assume_init()
+        };
+
+        Some(BuddyArena {
+            elements: new_elements,
+            bookkeeping,
+            strict_views,
+        })
+    }
+}
+
+impl<T> BuddyArena<MaybeUninit<T>> {
+    /// Creates an arena without constructing any elements up front.
+    ///
+    /// Every element starts out uninitialized, so a freshly allocated region must be written
+    /// to before it's read with `assume_init_*`. A region that was previously written to and
+    /// freed will still hold its old values, but nothing tracks that, so treat every new
+    /// allocation as uninitialized. The arena never drops the elements it holds.
+    pub fn new_uninit(
+        size: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> BuddyArena<MaybeUninit<T>> {
+        BuddyArena {
+            elements: Box::new_uninit_slice(size),
+            bookkeeping: BuddyBookkeeping::new(size, min_block_size, max_block_size),
+            strict_views: cfg!(debug_assertions),
+        }
+    }
+
+    /// Allocates `count` elements and initializes them with `T::default()`, so the whole view
+    /// is safe to `assume_init`. Any values left in the region from an earlier allocation are
+    /// overwritten without being dropped.
+    pub fn alloc_init(&mut self, count: usize) -> Option<Allocation>
+    where
+        T: Default,
+    {
+        let allocation = self.alloc(count)?;
+
+        for element in self.view_mut(&allocation) {
+            element.write(T::default());
+        }
+
+        Some(allocation)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// `size` was never set
+    MissingSize,
+    /// One of the sizes isn't a power of two
+    NotPowerOfTwo(usize),
+    /// `min_block_size` is bigger than `max_block_size`
+    MinAboveMax,
+    /// `max_block_size` is bigger than `size`
+    MaxAboveSize,
+    /// The element buffer couldn't be allocated
+    OutOfMemory(TryReserveError),
+}
+
+/// Configures a `BuddyArena` one option at a time. Only `size` has to be set: the minimum
+/// block size defaults to 1, and the maximum to the whole arena.
+pub struct BuddyArenaBuilder<T> {
+    size: Option<usize>,
+    min_block_size: usize,
+    max_block_size: Option<usize>,
+    node_capacity: usize,
+    strict_views: bool,
+    single_threaded: bool,
+    _elements: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for BuddyArenaBuilder<T> {
+    fn default() -> Self {
+        BuddyArenaBuilder {
+            size: None,
+            min_block_size: 1,
+            max_block_size: None,
+            node_capacity: 0,
+            strict_views: cfg!(debug_assertions),
+            single_threaded: false,
+            _elements: PhantomData,
+        }
+    }
+}
+
+impl<T> BuddyArenaBuilder<T> {
+    pub fn new() -> BuddyArenaBuilder<T> {
+        BuddyArenaBuilder::default()
+    }
+
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn min_block_size(mut self, min_block_size: usize) -> Self {
+        self.min_block_size = min_block_size;
+        self
+    }
+
+    pub fn max_block_size(mut self, max_block_size: usize) -> Self {
+        self.max_block_size = Some(max_block_size);
+        self
+    }
+
+    /// How many tree nodes to make room for up front, so early allocations don't have to
+    /// grow the bookkeeping
+    pub fn node_capacity(mut self, node_capacity: usize) -> Self {
+        self.node_capacity = node_capacity;
+        self
+    }
+
+    /// See `BuddyArena::set_strict_views`
+    pub fn strict_views(mut self, strict: bool) -> Self {
+        self.strict_views = strict;
+        self
+    }
+
+    /// See `BuddyBookkeeping::new_single_threaded`
+    pub fn single_threaded(mut self, single_threaded: bool) -> Self {
+        self.single_threaded = single_threaded;
+        self
+    }
+
+    pub fn build(self) -> Result<BuddyArena<T>, BuildError>
+    where
+        T: Default,
+    {
+        let size = self.size.ok_or(BuildError::MissingSize)?;
+        let max_block_size = self.max_block_size.unwrap_or(size);
+
+        for x in [size, self.min_block_size, max_block_size] {
+            if !is_pow_of_two(x) {
+                return Err(BuildError::NotPowerOfTwo(x));
+            }
+        }
+        if self.min_block_size > max_block_size {
+            return Err(BuildError::MinAboveMax);
+        }
+        if max_block_size > size {
+            return Err(BuildError::MaxAboveSize);
+        }
+
+        let mut elements_vec: Vec<T> = Vec::new();
+        elements_vec
+            .try_reserve_exact(size)
+            .map_err(BuildError::OutOfMemory)?;
+        elements_vec.extend(repeat_with(|| T::default()).take(size));
+
+        let mut bookkeeping = if self.single_threaded {
+            BuddyBookkeeping::new_single_threaded(size, self.min_block_size, max_block_size)
+        } else {
+            BuddyBookkeeping::new(size, self.min_block_size, max_block_size)
+        };
+        // reserving nothing leaves generational-arena's free list pointing past the end
+        if self.node_capacity > 0 {
+            bookkeeping.blocks.reserve(self.node_capacity);
+        }
+
+        Ok(BuddyArena {
+            elements: elements_vec.into(),
+            bookkeeping,
+            strict_views: self.strict_views,
+        })
+    }
+}
+
+#[test]
+#[allow(unused_variables, clippy::needless_as_bytes)]
+fn test() {
+    use crate::pretty_print::prettify;
+
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a1 = arena.alloc(64).unwrap();
+    let a2 = arena.alloc(24).unwrap();
+    let a3 = arena.alloc(2).unwrap();
+    let a4 = arena.alloc(7).unwrap();
+    let a5 = arena.alloc(31).unwrap();
+    let a6 = arena.alloc(60).unwrap();
+
+    println!("{:#?}", prettify(arena.bookkeeping()));
+
+    let string = "foobar";
+
+    let a = arena.alloc(string.bytes().len()).unwrap();
+    let view = arena.view_mut(&a);
+
+    view.copy_from_slice(string.as_bytes());
+
+    let str_view = std::str::from_utf8(view).unwrap();
+
+    dbg!(str_view);
+}
+
+#[test]
+fn test_new_uninit() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(u32);
+
+    impl Default for Counted {
+        fn default() -> Self {
+            CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+
+            Counted(7)
+        }
+    }
+
+    let mut arena: BuddyArena<MaybeUninit<Counted>> = BuddyArena::new_uninit(2048, 8, 256);
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), 0);
+
+    let a = arena.alloc_init(24).unwrap();
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), 24);
+
+    let view = arena.view(&a);
+    assert!(view.iter().all(|x| unsafe { x.assume_init_ref() }.0 == 7));
+}
+
+#[test]
+fn test_split_at() {
+    use crate::pretty_print::{prettify, PrettyState};
+
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a = arena.alloc(64).unwrap();
+    let (first, second) = a.split_at(16);
+    assert_eq!(first.range(), 0..16);
+    assert_eq!(second.range(), 16..64);
+
+    drop(first);
+    arena.tidy();
+    assert!(matches!(
+        prettify(arena.bookkeeping()).state,
+        PrettyState::Split(..)
+    ));
+
+    drop(second);
+    arena.tidy();
+    assert!(matches!(
+        prettify(arena.bookkeeping()).state,
+        PrettyState::Available
+    ));
+}
+
+#[test]
+fn test_snapshot() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _a1 = arena.alloc(64).unwrap();
+    let _a2 = arena.alloc(8).unwrap();
+
+    let snapshot = arena.bookkeeping().snapshot();
+    assert_eq!(snapshot.len(), arena.bookkeeping().blocks.len());
+    assert_eq!(snapshot[0], (0, 0..2048, 2));
+    assert_eq!(snapshot[1], (1, 0..1024, 2));
+    assert!(snapshot.contains(&(5, 0..64, 1)));
+}
+
+#[test]
+fn test_try_view() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let mut a = arena.alloc(64).unwrap();
+    assert_eq!(arena.try_view(&a).unwrap().len(), 64);
+    assert_eq!(arena.try_view_mut(&a).unwrap().len(), 64);
+
+    a.range = 2000..2100;
+    assert_eq!(arena.try_view(&a), Err(ViewError::OutOfBounds));
+    assert_eq!(arena.try_view_mut(&a), Err(ViewError::OutOfBounds));
+
+    a.range = 0..64;
+    a.index = Index::from_raw_parts(1000, 0);
+    assert_eq!(arena.try_view(&a), Err(ViewError::StaleIndex));
+    assert_eq!(arena.try_view_mut(&a), Err(ViewError::StaleIndex));
+}
+
+#[test]
+fn test_pressure_callback() {
+    use std::sync::Mutex;
+
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let fired_clone = fired.clone();
+
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    arena
+        .bookkeeping_mut()
+        .set_pressure_callback(0.5, Box::new(move |x| fired_clone.lock().unwrap().push(x)));
+
+    let mut allocations: Vec<_> = (0..4).map(|_| arena.alloc(256).unwrap()).collect();
+    assert!(fired.lock().unwrap().is_empty());
+
+    allocations.push(arena.alloc(256).unwrap());
+    allocations.push(arena.alloc(256).unwrap());
+    assert_eq!(*fired.lock().unwrap(), vec![0.625]);
+
+    allocations.truncate(3);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 768);
+
+    allocations.push(arena.alloc(256).unwrap());
+    allocations.push(arena.alloc(256).unwrap());
+    assert_eq!(*fired.lock().unwrap(), vec![0.625, 0.625]);
+}
+
+#[test]
+fn test_views_mut() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a1 = arena.alloc(64).unwrap();
+    let a2 = arena.alloc(8).unwrap();
+    let a3 = arena.alloc(100).unwrap();
+
+    // deliberately out of address order
+    let mut views = arena.views_mut(&[&a3, &a1, &a2]).unwrap();
+    views[0].fill(3);
+    views[1].fill(1);
+    views[2].fill(2);
+
+    assert!(arena.view(&a1).iter().all(|&x| x == 1));
+    assert!(arena.view(&a2).iter().all(|&x| x == 2));
+    assert!(arena.view(&a3).iter().all(|&x| x == 3));
+
+    assert!(arena.views_mut(&[&a1, &a2, &a1]).is_none());
+}
+
+#[test]
+fn test_size_classes() {
+    let arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    assert_eq!(
+        arena.bookkeeping().size_classes().collect::<Vec<_>>(),
+        vec![8, 16, 32, 64, 128, 256]
+    );
+
+    let arena: BuddyArena<u8> = BuddyArena::new(64, 64, 64);
+    assert_eq!(
+        arena.bookkeeping().size_classes().collect::<Vec<_>>(),
+        vec![64]
+    );
+}
+
+#[test]
+fn test_alloc_or_reuse() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let mut allocations: Vec<_> = (0..16).map(|_| arena.alloc(128).unwrap()).collect();
+
+    // free two buddies; they're still waiting in the channel
+    allocations.drain(4..6);
+
+    assert!(arena.alloc(256).is_none());
+    let reused = arena.alloc_or_reuse(256).unwrap();
+    assert_eq!(reused.range(), 512..768);
+    assert!(arena.alloc_or_reuse(256).is_none());
+}
+
+#[test]
+fn test_bookkeeping_bytes() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    let initial = arena.bookkeeping().bookkeeping_bytes();
+    assert!(initial > 0);
+
+    let allocations: Vec<_> = (0..64).map(|_| arena.alloc(8).unwrap()).collect();
+    let split = arena.bookkeeping().bookkeeping_bytes();
+    assert!(split > initial);
+
+    drop(allocations);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().blocks.len(), 1);
+    assert!(arena.bookkeeping().bookkeeping_bytes() <= split);
+}
+
+#[test]
+fn test_migrate() {
+    let mut source: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    let mut dest: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+
+    let _filler = dest.alloc(64).unwrap();
+
+    let a = source.alloc(6).unwrap();
+    source.view_mut(&a).copy_from_slice(b"foobar");
+
+    let a = source.migrate(&mut dest, a).unwrap();
+    assert_eq!(dest.view(&a), b"foobar");
+
+    source.tidy();
+    assert_eq!(source.bookkeeping().used_bytes(), 0);
+
+    let big = source.alloc(256).unwrap();
+    let big = source.migrate(&mut dest, big).unwrap_err();
+    assert_eq!(big.range(), 0..256);
+}
+
+#[test]
+fn test_single_block_tree() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(64, 64, 64);
+
+    let a = arena.alloc(10).unwrap();
+    assert_eq!(a.range(), 0..10);
+    assert!(arena.alloc(1).is_none());
+
+    arena.tidy();
+    arena.tidy_gas(10);
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..64, 1)]);
+
+    drop(a);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..64, 0)]);
+
+    let a = arena.alloc(64).unwrap();
+    assert_eq!(a.range(), 0..64);
+    assert!(arena.alloc(65).is_none());
+}
+
+#[test]
+fn test_full_size_allocation() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 2048);
+
+    let a = arena.alloc(2048).unwrap();
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..2048, 1)]);
+    assert!(arena.alloc(8).is_none());
+
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..2048, 1)]);
+
+    drop(a);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..2048, 0)]);
+    assert!(arena.alloc(8).is_some());
+}
+
+#[test]
+fn test_reserve() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let reservation = arena.bookkeeping_mut().reserve(64).unwrap();
+    assert_eq!(reservation.range(), 0..64);
+    assert_eq!(arena.bookkeeping().used_bytes(), 64);
+
+    reservation.cancel();
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 0);
+
+    let dropped = arena.bookkeeping_mut().reserve(64).unwrap();
+    drop(dropped);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 0);
+
+    let a = arena.bookkeeping_mut().reserve(64).unwrap().commit();
+    arena.tidy();
+    assert_eq!(a.range(), 0..64);
+    assert_eq!(arena.bookkeeping().used_bytes(), 64);
+}
+
+#[test]
+fn test_tidy_bias() {
+    fn fragmented() -> BuddyBookkeeping {
+        let mut bookkeeping = BuddyBookkeeping::new(64, 8, 64);
+        let allocations: Vec<_> = (0..8).map(|_| bookkeeping.alloc(8).unwrap()).collect();
+        drop(allocations);
+
+        bookkeeping
+    }
+
+    // 8 gas to process the frees, and 8 more to walk one half of the tree
+    let mut left = fragmented();
+    left.tidy_leftmost(16);
+    let snapshot = left.snapshot();
+    assert!(snapshot.contains(&(1, 0..32, 0)));
+    assert!(snapshot.contains(&(1, 32..64, 2)));
+
+    let mut right = fragmented();
+    right.tidy_rightmost(16);
+    let snapshot = right.snapshot();
+    assert!(snapshot.contains(&(1, 0..32, 2)));
+    assert!(snapshot.contains(&(1, 32..64, 0)));
+}
+
+#[test]
+fn test_path_to() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _a1 = arena.alloc(64).unwrap();
+    let a2 = arena.alloc(8).unwrap();
+
+    let bookkeeping = arena.bookkeeping();
+    let path = bookkeeping.path_to(a2.index).unwrap();
+
+    assert_eq!(path.first(), Some(&bookkeeping.root));
+    assert_eq!(path.last(), Some(&a2.index));
+    assert_eq!(path.len(), 9);
+
+    for pair in path.windows(2) {
+        match bookkeeping.blocks[pair[0]].state {
+            BlockState::Split(first, second) => assert!(pair[1] == first || pair[1] == second),
+            _ => panic!("parent isn't split"),
+        }
+    }
+
+    assert_eq!(bookkeeping.path_to(Index::from_raw_parts(1000, 0)), None);
+}
+
+#[test]
+fn test_view_range() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _filler = arena.alloc(64).unwrap();
+    let a = arena.alloc(64).unwrap();
+    for (i, x) in arena.view_mut(&a).iter_mut().enumerate() {
+        *x = i as u8;
+    }
+
+    assert_eq!(arena.view_range(&a, 10..14), Some(&[10, 11, 12, 13][..]));
+    arena.view_range_mut(&a, 10..14).unwrap().fill(0);
+    assert_eq!(arena.view_range(&a, 9..15), Some(&[9, 0, 0, 0, 0, 14][..]));
+
+    assert_eq!(arena.view_range(&a, 60..65), None);
+    assert_eq!(arena.view_range(&a, 64..64), Some(&[][..]));
+    assert!(arena.view_range_mut(&a, 0..100).is_none());
+}
+
+#[test]
+fn test_rounded_size_for() {
+    let bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+
+    // (count, rounded size)
+    let cases = [
+        (0, None),
+        (1, Some(8)),
+        (2, Some(8)),
+        (8, Some(8)),
+        (9, Some(16)),
+        (24, Some(32)),
+        (64, Some(64)),
+        (65, Some(128)),
+        (255, Some(256)),
+        (256, Some(256)),
+        (257, None),
+        (2048, None),
+        (usize::MAX, None),
+    ];
+
+    for (count, expected) in cases {
+        assert_eq!(
+            bookkeeping.rounded_size_for(count),
+            expected,
+            "count {}",
+            count
+        );
+    }
+
+    let mut bookkeeping = bookkeeping;
+    assert_eq!(bookkeeping.alloc(2).unwrap().range(), 0..2);
+    assert!(bookkeeping.alloc(257).is_none());
+    assert!(bookkeeping.alloc(0).is_none());
+}
+
+#[test]
+fn test_tidy_step() {
+    let mut bookkeeping = BuddyBookkeeping::new(64, 8, 64);
+    let allocations: Vec<_> = (0..8).map(|_| bookkeeping.alloc(8).unwrap()).collect();
+    drop(allocations);
+
+    // 8 frees, 8 leaves, and 7 split blocks visited on the way down and back up
+    let needed: usize = 8 + 8 + 7 * 2;
+
+    let mut calls = 0;
+    while bookkeeping.blocks.len() > 1 {
+        bookkeeping.tidy_step(3);
+        calls += 1;
+
+        assert!(
+            calls <= needed.div_ceil(3),
+            "tidy_step isn't making progress"
+        );
+    }
+
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..64, 0)]);
+}
+
+#[test]
+fn test_alloc_aligned() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _a1 = arena.alloc(8).unwrap();
+    let a2 = arena.alloc_aligned_const::<64>(3).unwrap();
+    assert_eq!(a2.range().start % 64, 0);
+    assert_eq!(a2.range().len(), 3);
+    assert_eq!(arena.view(&a2).len(), 3);
+
+    let a3 = arena.alloc_aligned(8, 128).unwrap();
+    assert_eq!(a3.range().start % 128, 0);
+
+    assert!(arena.alloc_aligned(8, 512).is_none());
+}
+
+#[test]
+fn test_internal_fragmentation() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    assert_eq!(arena.bookkeeping().internal_fragmentation(), 0);
+
+    let _a1 = arena.alloc(24).unwrap(); // 32 - 24
+    let a2 = arena.alloc(2).unwrap(); // 8 - 2
+    let _a3 = arena.alloc(65).unwrap(); // 128 - 65
+    let _a4 = arena.alloc(64).unwrap(); // 64 - 64
+    assert_eq!(arena.bookkeeping().internal_fragmentation(), 8 + 6 + 63);
+
+    drop(a2);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().internal_fragmentation(), 8 + 63);
+}
+
+#[test]
+fn test_transmute_elements() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(1024, 8, 256);
+
+    let _a1 = arena.alloc(64).unwrap();
+    let a2 = arena.alloc(8).unwrap();
+    arena
+        .view_mut(&a2)
+        .copy_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(a2.range(), 64..72);
+
+    let arena: BuddyArena<u32> = unsafe { arena.transmute_elements() }.unwrap();
+    let a2 = a2.transmute_elements::<u8, u32>().unwrap();
+    assert_eq!(a2.range(), 16..18);
+    assert_eq!(
+        arena.view(&a2),
+        &[
+            u32::from_ne_bytes([1, 0, 0, 0]),
+            u32::from_ne_bytes([2, 0, 0, 0])
+        ]
+    );
+    assert_eq!(
+        arena.bookkeeping().size_classes().collect::<Vec<_>>(),
+        vec![2, 4, 8, 16, 32, 64]
+    );
+    assert!(arena.bookkeeping().snapshot().contains(&(4, 0..16, 1)));
+
+    // a 6 byte allocation can't be expressed in u32s
+    let mut arena: BuddyArena<u8> = BuddyArena::new(1024, 8, 256);
+    let _odd = arena.alloc(6).unwrap();
+    assert!(unsafe { arena.transmute_elements::<u32>() }.is_none());
+}
+
+#[test]
+fn test_transmute_padded_with_base_offset() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(1024, 8, 256);
+    arena.bookkeeping_mut().set_base_offset(2048);
+
+    let _a1 = arena.alloc(64).unwrap();
+    let a2 = arena.alloc_padded(12).unwrap();
+    arena.view_mut(&a2)[..12].copy_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+    assert_eq!(a2.range(), 2112..2128);
+
+    let arena: BuddyArena<u32> = unsafe { arena.transmute_elements() }.unwrap();
+    assert_eq!(arena.bookkeeping().base_offset(), 512);
+
+    let a2 = a2.transmute_elements::<u8, u32>().unwrap();
+    assert_eq!(a2.range(), 528..532);
+    assert_eq!(a2.logical_len(), 3);
+    assert_eq!(
+        arena.view(&a2)[..a2.logical_len()],
+        [1, 2, 3].map(|x| u32::from_ne_bytes([x, 0, 0, 0]))
+    );
+
+    // 5 of 8 bytes isn't a whole number of u32s
+    let mut arena: BuddyArena<u8> = BuddyArena::new(1024, 8, 256);
+    let odd = arena.alloc_padded(5).unwrap();
+    let odd = odd.transmute_elements::<u8, u32>().unwrap_err();
+    assert_eq!((odd.range(), odd.logical_len()), (0..8, 5));
+}
+
+#[test]
+fn test_layout() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let mut allocations: Vec<_> = (0..4).map(|_| Some(arena.alloc(256).unwrap())).collect();
+    allocations[1] = None;
+    allocations[3] = None;
+    arena.tidy();
+
+    let layout: Vec<_> = arena.bookkeeping().layout(false).collect();
+    assert_eq!(layout.len(), 5);
+    assert_eq!(layout.first().unwrap().range.start, 0);
+    assert_eq!(layout.last().unwrap().range.end, 2048);
+    assert!(layout
+        .windows(2)
+        .all(|pair| pair[0].range.end == pair[1].range.start));
+
+    let merged: Vec<_> = arena.bookkeeping().layout(true).collect();
+    assert_eq!(
+        merged,
+        vec![
+            LayoutEntry {
+                range: 0..256,
+                occupied: true
+            },
+            LayoutEntry {
+                range: 256..512,
+                occupied: false
+            },
+            LayoutEntry {
+                range: 512..768,
+                occupied: true
+            },
+            LayoutEntry {
+                range: 768..2048,
+                occupied: false
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_shrink_bookkeeping() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let allocations: Vec<_> = (0..256).map(|_| arena.alloc(8).unwrap()).collect();
+    drop(allocations);
+    arena.tidy_gas(256 + 8);
+
+    let capacity = arena.bookkeeping().blocks.capacity();
+    let snapshot = arena.bookkeeping().snapshot();
+
+    arena.bookkeeping_mut().shrink_bookkeeping();
+    assert!(arena.bookkeeping().blocks.capacity() < capacity);
+    assert_eq!(arena.bookkeeping().snapshot(), snapshot);
+
+    arena.tidy();
+    arena.bookkeeping_mut().shrink_bookkeeping();
+    assert_eq!(arena.bookkeeping().blocks.capacity(), 1);
+    assert!(arena.alloc(8).is_some());
+}
+
+#[test]
+fn test_shrink_bookkeeping_after_reset() {
+    let mut bk = BuddyBookkeeping::new(64, 8, 64);
+    let stale: Vec<_> = (0..8).map(|_| bk.alloc(8).unwrap()).collect();
+
+    bk.reset();
+    bk.tidy();
+    bk.shrink_bookkeeping();
+
+    let fresh: Vec<_> = (0..8).map(|_| bk.alloc(8).unwrap()).collect();
+    drop(stale);
+    bk.tidy();
+
+    // the old handles didn't free the new blocks
+    assert_eq!(bk.live_allocations(), 8);
+    assert_eq!(bk.used_bytes(), 64);
+    drop(fresh);
+}
+
+#[test]
+fn test_shrink_bookkeeping_checkpoint() {
+    let mut bk = BuddyBookkeeping::new(64, 8, 64);
+    let checkpoint = bk.checkpoint();
+
+    drop(bk.alloc(8).unwrap());
+    bk.tidy();
+    bk.shrink_bookkeeping();
+
+    let stale = bk.alloc(16).unwrap();
+    bk.rewind(checkpoint);
+    assert_eq!(bk.snapshot(), vec![(0, 0..64, 0)]);
+
+    let fresh = bk.alloc(16).unwrap();
+    assert_ne!(fresh.index, stale.index);
+    drop(stale);
+    bk.tidy();
+    assert_eq!(bk.live_allocations(), 1);
+    drop(fresh);
+}
+
+#[test]
+fn test_shrink_bookkeeping_recycled() {
+    let mut bk = BuddyBookkeeping::new(64, 8, 64);
+    bk.set_recycle_capacity(32, 4);
+
+    drop(bk.alloc(32).unwrap());
+    bk.tidy();
+    assert_eq!(bk.used_bytes(), 32);
+
+    bk.shrink_bookkeeping();
+    assert_eq!(bk.used_bytes(), 0);
+    bk.tidy();
+    assert!(bk.alloc(64).is_some());
+}
+
+#[test]
+fn test_alloc_exact() {
+    let mut exact: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    let mut rounded: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a = exact.alloc_exact(32).unwrap();
+    let b = rounded.alloc(32).unwrap();
+    assert_eq!(a.range(), b.range());
+    assert_eq!(
+        exact.bookkeeping().snapshot(),
+        rounded.bookkeeping().snapshot()
+    );
+
+    let a = exact.alloc_exact(8).unwrap();
+    assert_eq!(a.range().len(), 8);
+}
+
+#[test]
+fn test_compaction_benefit() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    assert_eq!(arena.bookkeeping().compaction_benefit(), 0);
+
+    let mut allocations: Vec<_> = (0..8).map(|_| Some(arena.alloc(256).unwrap())).collect();
+    assert!(arena.alloc(8).is_none());
+
+    for i in [0, 2, 3, 5, 6] {
+        allocations[i] = None;
+    }
+    arena.tidy();
+
+    // 1 occupied block of 256 at 256, 1024, and 1792, so nothing is free at the end
+    assert_eq!(arena.bookkeeping().compaction_benefit(), 2048 - 3 * 256);
+
+    // packing the same blocks into a fresh arena is what a compaction would produce
+    let mut packed = BuddyBookkeeping::new(2048, 8, 256);
+    let _packed: Vec<_> = (0..3).map(|_| packed.alloc(256).unwrap()).collect();
+    let tail = packed.layout(true).last().unwrap();
+    assert!(!tail.occupied);
+    assert_eq!(tail.range.len(), arena.bookkeeping().compaction_benefit());
+    assert_eq!(packed.compaction_benefit(), 0);
+}
+
+#[test]
+fn test_reset() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _filler = arena.alloc(64).unwrap();
+    let a = arena.alloc(6).unwrap();
+    arena.view_mut(&a).copy_from_slice(b"foobar");
+    let range = a.range();
+
+    arena.reset_keep_data();
+    assert_eq!(arena.bookkeeping().used_bytes(), 0);
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..2048, 0)]);
+
+    let _filler = arena.alloc(64).unwrap();
+    let b = arena.alloc(6).unwrap();
+    assert_eq!(b.range(), range);
+    assert_eq!(arena.view(&b), b"foobar");
+
+    // stale handles from before the reset don't free anything
+    drop(a);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 64 + 8);
+
+    arena.reset();
+    let _filler = arena.alloc(64).unwrap();
+    let c = arena.alloc(6).unwrap();
+    assert_eq!(arena.view(&c), &[0; 6]);
+}
+
+#[test]
+fn test_gather_scatter() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a1 = arena.alloc(3).unwrap();
+    let a2 = arena.alloc(20).unwrap();
+    let a3 = arena.alloc(5).unwrap();
+
+    let input: Vec<u8> = (0..28).collect();
+    assert_eq!(arena.scatter_from(&[&a1, &a2, &a3], &input[..27]), None);
+    assert_eq!(arena.scatter_from(&[&a1, &a2, &a3], &input), Some(28));
+    assert_eq!(arena.view(&a2)[0], 3);
+
+    let mut out = [0; 28];
+    assert_eq!(arena.gather_into(&[&a1, &a2, &a3], &mut out[..27]), None);
+    assert_eq!(arena.gather_into(&[&a1, &a2, &a3], &mut out), Some(28));
+    assert_eq!(&out[..], &input[..]);
+}
+
+#[test]
+fn test_weak_allocation() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _filler = arena.alloc(8).unwrap();
+    let a = arena.alloc(6).unwrap();
+    arena.view_mut(&a).copy_from_slice(b"foobar");
+
+    let weak = a.downgrade();
+    assert_eq!(arena.upgrade_view(&weak), Some(&b"foobar"[..]));
+
+    drop(a);
+    assert!(arena.upgrade_view(&weak).is_some());
+    arena.tidy();
+    assert_eq!(arena.upgrade_view(&weak), None);
+
+    // the same block getting reused by a new allocation doesn't revive the weak handle
+    let b = arena.alloc(6).unwrap();
+    assert_eq!(b.range(), weak.range);
+    assert_eq!(arena.upgrade_view(&weak), None);
+}
+
+#[test]
+fn test_recycle() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    arena.bookkeeping_mut().set_recycle_capacity(32, 1);
+
+    let _filler = arena.alloc(8).unwrap();
+    let a = arena.alloc(32).unwrap();
+    let index = a.index;
+    drop(a);
+    arena.tidy();
+
+    let snapshot = arena.bookkeeping().snapshot();
+    for _ in 0..4 {
+        let a = arena.alloc(20).unwrap();
+        assert_eq!(a.index, index);
+        assert_eq!(a.range(), 32..52);
+        assert_eq!(arena.bookkeeping().snapshot(), snapshot);
+
+        drop(a);
+        arena.tidy();
+    }
+
+    // nothing reused it since the last tidy, so it goes back to the tree
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 8);
+    drop(_filler);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().snapshot(), vec![(0, 0..2048, 0)]);
+}
+
+#[test]
+fn test_alloc_stack() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+
+    let sizes = [8, 64, 24, 256, 8, 100];
+    let mut allocations: Vec<_> = sizes
+        .iter()
+        .map(|&size| bookkeeping.alloc_stack(size).unwrap())
+        .collect();
+
+    assert_eq!(allocations[0].range().start, 0);
+    assert!(bookkeeping.used_bytes() > 0);
+
+    while let Some(allocation) = allocations.pop() {
+        bookkeeping.free_stack(allocation);
+    }
+
+    // no tidy needed
+    assert_eq!(bookkeeping.used_bytes(), 0);
+    assert_eq!(bookkeeping.live_allocations(), 0);
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..2048, 0)]);
+}
+
+#[test]
+fn test_free_stack_eager_coalesce() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+    bookkeeping.set_eager_coalesce_above(8);
+
+    let a = bookkeeping.alloc_stack(8).unwrap();
+    let b = bookkeeping.alloc_stack(8).unwrap();
+    bookkeeping.free_stack(b);
+    bookkeeping.free_stack(a);
+
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..2048, 0)]);
+}
+
+#[test]
+fn test_alloc_stack_skips_recycled() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    bookkeeping.set_recycle_capacity(8, 1);
+
+    let a = bookkeeping.alloc(8).unwrap();
+    let _b = bookkeeping.alloc(8).unwrap();
+    let c = bookkeeping.alloc(8).unwrap();
+
+    // c is freed first, so it's the one kept in the recycle list
+    drop(c);
+    drop(a);
+    bookkeeping.tidy();
+
+    assert_eq!(bookkeeping.alloc_stack(8).unwrap().range(), 0..8);
+    assert_eq!(bookkeeping.alloc(8).unwrap().range(), 16..24);
+}
+
+#[test]
+fn test_stack_dropped_then_freed() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+
+    let a = bookkeeping.alloc_stack(8).unwrap();
+    let b = bookkeeping.alloc_stack(64).unwrap();
+
+    // a plain drop instead of `free_stack` still takes b off the stack
+    drop(b);
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.stack.len(), 1);
+
+    bookkeeping.free_stack(a);
+    assert!(bookkeeping.stack.is_empty());
+    assert_eq!(bookkeeping.live_allocations(), 0);
+}
+
+#[cfg(not(debug_assertions))]
+#[test]
+fn test_free_stack_out_of_order() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+
+    let a = bookkeeping.alloc_stack(8).unwrap();
+    let b = bookkeeping.alloc_stack(64).unwrap();
+    bookkeeping.free_stack(a);
+    assert_eq!(bookkeeping.stack, vec![b.index]);
+    assert_eq!(bookkeeping.live_allocations(), 1);
+
+    bookkeeping.free_stack(b);
+    assert!(bookkeeping.stack.is_empty());
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..2048, 0)]);
+}
+
+#[test]
+#[should_panic(expected = "viewed an allocation that has been freed")]
+fn test_strict_views() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    arena.set_strict_views(true);
+
+    let a = arena.alloc(8).unwrap();
+    arena.reset_keep_data();
+
+    arena.view(&a);
+}
+
+#[test]
+fn test_lenient_views() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    arena.set_strict_views(false);
+
+    let a = arena.alloc(8).unwrap();
+    arena.reset_keep_data();
+
+    assert_eq!(arena.view(&a).len(), 8);
+}
+
+#[test]
+fn test_max_coalescible_free() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let mut allocations: Vec<_> = (0..16).map(|_| Some(arena.alloc(128).unwrap())).collect();
+    assert_eq!(arena.bookkeeping().max_coalescible_free(), 0);
+
+    // one free block that's already been tidied, and one free buddy still in the channel
+    allocations[6] = None;
+    arena.tidy();
+    allocations[7] = None;
+
+    assert!(arena.alloc(256).is_none());
+    assert_eq!(arena.bookkeeping().max_coalescible_free(), 256);
+
+    // the query didn't lose the pending free
+    arena.tidy();
+    assert_eq!(arena.alloc(256).unwrap().range(), 768..1024);
+}
+
+#[test]
+fn test_alloc_pending() {
+    let mut bookkeeping = BuddyBookkeeping::new(512, 8, 256);
+
+    let a1 = bookkeeping.alloc_pending(256).unwrap();
+    let _a2 = bookkeeping.alloc_pending(256).unwrap();
+
+    let mut pending = bookkeeping.alloc_pending(200).unwrap_err();
+    assert_eq!(pending.count(), 200);
+    assert!(bookkeeping.poll_pending(&mut pending).is_none());
+
+    drop(a1);
+    assert!(bookkeeping.poll_pending(&mut pending).is_none());
+
+    bookkeeping.tidy();
+    let a3 = bookkeeping.poll_pending(&mut pending).unwrap();
+    assert_eq!(a3.range(), 0..200);
+}
+
+#[test]
+fn test_resettable() {
+    // keeps its capacity, unlike replacing it with a default
+    struct Buffer(Vec<u8>);
+
+    impl Resettable for Buffer {
+        fn reset(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    let buffers: Vec<Buffer> = (0..64).map(|_| Buffer(Vec::with_capacity(16))).collect();
+    let mut arena = BuddyArena {
+        elements: buffers.into(),
+        bookkeeping: BuddyBookkeeping::new(64, 8, 64),
+        strict_views: true,
+    };
+
+    let a = arena.alloc_zeroed(8).unwrap();
+    let range = a.range();
+    for buffer in arena.view_mut(&a) {
+        buffer.0.extend_from_slice(b"data");
+    }
+
+    drop(a);
+    arena.tidy_zeroize();
+
+    assert!(arena.elements[range]
+        .iter()
+        .all(|buffer| buffer.0.is_empty() && buffer.0.capacity() >= 16));
+
+    let mut defaults: BuddyArena<u8> = BuddyArena::new(64, 8, 64);
+    let a = defaults.alloc(8).unwrap();
+    defaults.view_mut(&a).fill(5);
+    drop(a);
+    defaults.tidy_zeroize();
+
+    let a = defaults.alloc(8).unwrap();
+    assert_eq!(defaults.view(&a), &[0; 8]);
+}
+
+#[test]
+fn test_new_pow2() {
+    let mut bookkeeping = BuddyBookkeeping::new_pow2(
+        Pow2::new_const(2048),
+        Pow2::new_const(8),
+        Pow2::new(256).unwrap(),
+    );
+
+    assert_eq!(bookkeeping.alloc(3).unwrap().range(), 0..3);
+    assert_eq!(
+        bookkeeping.size_classes().collect::<Vec<_>>(),
+        vec![8, 16, 32, 64, 128, 256]
+    );
+}
+
+#[test]
+fn test_max_allocations() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+    bookkeeping.set_max_allocations(Some(3));
+
+    let mut allocations: Vec<_> = (0..3).map(|_| bookkeeping.alloc(8).unwrap()).collect();
+    assert_eq!(bookkeeping.live_allocations(), 3);
+    assert!(bookkeeping.alloc(8).is_none());
+
+    allocations.pop();
+    assert!(bookkeeping.alloc(8).is_none());
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.live_allocations(), 2);
+    allocations.push(bookkeeping.alloc(8).unwrap());
+
+    bookkeeping.set_max_allocations(None);
+    allocations.push(bookkeeping.alloc(8).unwrap());
+    assert_eq!(bookkeeping.live_allocations(), 4);
+}
+
+#[test]
+fn test_plan() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 256);
+    let _a = bookkeeping.alloc(256).unwrap();
+
+    assert!(bookkeeping.plan(&[256, 256, 256]));
+    assert!(bookkeeping.plan(&[256, 128, 64, 64, 256]));
+
+    // each one fits on its own, but not all together
+    assert!(bookkeeping.plan(&[200]));
+    assert!(!bookkeeping.plan(&[200, 200, 200, 200]));
+    assert!(!bookkeeping.plan(&[300]));
+
+    // nothing was actually allocated
+    assert_eq!(bookkeeping.used_bytes(), 256);
+    assert_eq!(bookkeeping.blocks.len(), 5);
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_fail_after() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+    bookkeeping.set_fail_after(2);
+
+    let _a1 = bookkeeping.alloc(8).unwrap();
+    let _a2 = bookkeeping.alloc(8).unwrap();
+    assert!(bookkeeping.alloc(8).is_none());
+    assert!(bookkeeping.alloc(8).is_none());
+
+    bookkeeping.set_fail_after(usize::MAX);
+    let _a3 = bookkeeping.alloc(8).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_fail_after_realloc_in_place() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+    arena.bookkeeping_mut().set_fail_after(2);
+
+    // resizing within the block doesn't use up an allocation
+    let a = arena.alloc(8).unwrap();
+    let a = arena.realloc(a, 4).unwrap();
+    let _a = arena.realloc(a, 8).unwrap();
+
+    let _b = arena.alloc(8).unwrap();
+    assert!(arena.alloc(8).is_none());
+}
+
+#[test]
+fn test_alloc_latencies() {
+    let mut bookkeeping = BuddyBookkeeping::new(2048, 8, 256);
+
+    let _a1 = bookkeeping.alloc(8).unwrap();
+    assert!(bookkeeping.recent_alloc_latencies().is_empty());
+
+    bookkeeping.set_latency_tracking(Some(4));
+    let _a2 = bookkeeping.alloc(8).unwrap();
+    let _a3 = bookkeeping.alloc(8).unwrap();
+    assert_eq!(bookkeeping.recent_alloc_latencies().len(), 2);
+
+    let _more: Vec<_> = (0..10).map(|_| bookkeeping.alloc(8).unwrap()).collect();
+    assert_eq!(bookkeeping.recent_alloc_latencies().len(), 4);
+
+    bookkeeping.set_latency_tracking(None);
+    assert!(bookkeeping.recent_alloc_latencies().is_empty());
+}
+
+#[test]
+fn test_drop_order() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let mut arena: BuddyArena<Counted> = BuddyArena::new(64, 8, 64);
+    let a = arena.alloc(8).unwrap();
+    let b = arena.alloc(16).unwrap();
+    drop(b);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+
+    drop(arena);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 64);
+
+    drop(a);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 64);
+
+    // uninitialized arenas never drop their elements
+    let mut arena: BuddyArena<MaybeUninit<Counted>> = BuddyArena::new_uninit(64, 8, 64);
+    let _a = arena.alloc_init(8).unwrap();
+    drop(arena);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 64);
+}
+
+#[test]
+fn test_view_array() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let a = arena.alloc(4).unwrap();
+    *arena.view_array_mut::<4>(&a).unwrap() = [1, 2, 3, 4];
+
+    assert_eq!(arena.view_array::<4>(&a), Some(&[1, 2, 3, 4]));
+    assert_eq!(arena.view_array::<5>(&a), None);
+    assert!(arena.view_array_mut::<8>(&a).is_none());
+}
+
+#[test]
+fn test_tidy_min_size() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let mut allocations: Vec<_> = (0..64).map(|_| Some(arena.alloc(8).unwrap())).collect();
+
+    // keep one block in the second 256 alive, and free everything else
+    let _kept = allocations[32].take();
+    allocations.clear();
+    arena.tidy_min_size(256);
+
+    let snapshot = arena.bookkeeping().snapshot();
+    assert!(snapshot.contains(&(3, 0..256, 0)));
+    assert!(snapshot.contains(&(7, 256..272, 2)));
+    assert!(snapshot.contains(&(8, 264..272, 0)));
+    assert!(snapshot.contains(&(7, 272..288, 2)));
+
+    arena.tidy();
+    let snapshot = arena.bookkeeping().snapshot();
+    assert!(snapshot.contains(&(7, 272..288, 0)));
+}
+
+#[test]
+fn test_alloc_with_meta() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+
+    let named = arena.alloc_with_meta(16, String::from("vertices")).unwrap();
+    let plain = arena.alloc(16).unwrap();
+
+    let bookkeeping = arena.bookkeeping();
+    assert_eq!(
+        bookkeeping.meta::<String>(&named).map(String::as_str),
+        Some("vertices")
+    );
+    assert_eq!(bookkeeping.meta::<u32>(&named), None);
+    assert_eq!(bookkeeping.meta::<String>(&plain), None);
+
+    // the metadata goes away with the block, and doesn't carry over to whoever reuses it
+    drop(named);
+    arena.tidy();
+    let reused = arena.alloc(16).unwrap();
+    assert_eq!(arena.bookkeeping().meta::<String>(&reused), None);
+}
+
+#[test]
+fn test_alignment_overhead() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(4096, 8, 4096);
+
+    let _allocations: Vec<_> = [3, 8, 12, 20]
+        .into_iter()
+        .map(|count| arena.alloc(count).unwrap())
+        .collect();
+
+    // blocks are 8, 8, 16 and 32 bytes, all aligned to at least 8 already
+    let bookkeeping = arena.bookkeeping();
+    assert_eq!(bookkeeping.alignment_overhead(8), 0);
+    assert_eq!(
+        bookkeeping.alignment_overhead(256),
+        (256 - 8) + (256 - 8) + (256 - 16) + (256 - 32)
+    );
+}
+
+#[test]
+fn test_is_range_occupied() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+
+    let first = bookkeeping.alloc(32).unwrap();
+    let second = bookkeeping.alloc(32).unwrap();
+    assert_eq!((first.range(), second.range()), (0..32, 32..64));
+
+    assert!(bookkeeping.is_range_occupied(0..64));
+    assert!(bookkeeping.is_range_occupied(10..40));
+    // crosses from the second allocation into free space
+    assert!(!bookkeeping.is_range_occupied(48..80));
+    assert!(!bookkeeping.is_range_occupied(200..300));
+}
+
+#[test]
+fn test_alloc_colored() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 1024);
+
+    let colors = 4;
+    let allocations: Vec<_> = (0..8)
+        .map(|i| bookkeeping.alloc_colored(16, i % colors, colors).unwrap())
+        .collect();
+
+    for (i, allocation) in allocations.iter().enumerate() {
+        assert_eq!(allocation.range().start / 16 % colors, i % colors);
+    }
+
+    // once a color runs out, it falls back to whatever's free
+    let mut small = BuddyBookkeeping::new(64, 16, 64);
+    let _first = small.alloc_colored(16, 0, 2).unwrap();
+    let _second = small.alloc_colored(16, 0, 2).unwrap();
+    let fallback = small.alloc_colored(16, 0, 2).unwrap();
+    assert_eq!(fallback.range().start / 16 % 2, 1);
+}
+
+#[test]
+fn test_checkpoint_rewind() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+
+    let kept = bookkeeping.alloc(64).unwrap();
+    let checkpoint = bookkeeping.checkpoint();
+
+    let speculative: Vec<_> = (0..3).map(|_| bookkeeping.alloc(64).unwrap()).collect();
+    assert!(bookkeeping.alloc(64).is_none());
+
+    bookkeeping.rewind(checkpoint);
+    assert_eq!(bookkeeping.used_bytes(), 64);
+    assert!(bookkeeping.is_range_occupied(kept.range()));
+    assert!(!bookkeeping.is_range_occupied(64..128));
+
+    let reused: Vec<_> = (0..3).map(|_| bookkeeping.alloc(64).unwrap()).collect();
+
+    // the old handles don't free anything in the restored tree
+    drop(speculative);
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 256);
+
+    drop((kept, reused));
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 0);
+}
+
+#[test]
+fn test_rewind_recycled() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    bookkeeping.set_recycle_capacity(32, 1);
+
+    drop(bookkeeping.alloc(20).unwrap());
+    bookkeeping.tidy();
+    let checkpoint = bookkeeping.checkpoint();
+
+    let speculative = bookkeeping.alloc(20).unwrap();
+    bookkeeping.rewind(checkpoint);
+    assert_eq!(bookkeeping.used_bytes(), 32);
+    assert_eq!(bookkeeping.live_allocations(), 0);
+
+    // the recycled block is back in its list, and the handle from since doesn't free it
+    drop(speculative);
+    bookkeeping.tidy_gas(1);
+    let snapshot = bookkeeping.snapshot();
+    let reused = bookkeeping.alloc(20).unwrap();
+    assert_eq!(reused.range(), 0..20);
+    assert_eq!(bookkeeping.snapshot(), snapshot);
+}
+
+#[test]
+fn test_rewind_keeps_frees() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+
+    let kept = bookkeeping.alloc(64).unwrap();
+    let freed = bookkeeping.alloc(64).unwrap();
+    let checkpoint = bookkeeping.checkpoint();
+
+    drop(freed);
+    bookkeeping.tidy();
+    let _speculative = bookkeeping.alloc(128).unwrap();
+
+    bookkeeping.rewind(checkpoint);
+    assert_eq!(bookkeeping.used_bytes(), 64);
+    assert_eq!(bookkeeping.live_allocations(), 1);
+    assert!(bookkeeping.is_range_occupied(kept.range()));
+    assert!(!bookkeeping.is_range_occupied(64..128));
+
+    drop(kept);
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..256, 0)]);
+}
+
+#[test]
+fn test_try_new() {
+    let arena = BuddyArena::<u8>::try_new(256, 8, 256).unwrap();
+    assert_eq!(arena.bookkeeping().size_classes().last(), Some(256));
+
+    assert_eq!(
+        BuddyArena::<u8>::try_new(256, 8, 48).err(),
+        Some(TryNewError::InvalidSizes)
+    );
+    assert!(matches!(
+        BuddyArena::<u64>::try_new(1 << 62, 8, 256),
+        Err(TryNewError::OutOfMemory(_))
+    ));
+}
+
+#[test]
+fn test_prefetch() {
+    let mut arena: BuddyArena<u64> = BuddyArena::new(1024, 8, 1024);
+
+    let allocation = arena.alloc(300).unwrap();
+    arena.view_mut(&allocation).fill(7);
+
+    arena.prefetch(&allocation);
+    assert!(arena.view(&allocation).iter().all(|&x| x == 7));
+}
+
+#[test]
+fn test_size_class() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 1024);
+
+    for (count, class) in [(1, 3), (8, 3), (9, 4), (100, 7), (512, 9)] {
+        let allocation = bookkeeping.alloc(count).unwrap();
+        assert_eq!(allocation.size_class(&bookkeeping), class);
+    }
+}
+
+#[test]
+fn test_freeze() {
+    use crate::pretty_print::prettify;
+
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    let _first = bookkeeping.alloc(20).unwrap();
+    let second = bookkeeping.alloc(64).unwrap();
+
+    let frozen = bookkeeping.freeze();
+    assert_eq!(frozen.snapshot(), bookkeeping.snapshot());
+    assert!(frozen.layout(true).eq(bookkeeping.layout(true)));
+    assert_eq!(frozen.used_bytes(), bookkeeping.used_bytes());
+    assert_eq!(
+        prettify(&frozen).to_string(),
+        prettify(&bookkeeping).to_string()
+    );
+
+    // the snapshot doesn't follow later changes
+    drop(second);
+    bookkeeping.tidy();
+    assert_ne!(frozen.used_bytes(), bookkeeping.used_bytes());
+    assert!(frozen.is_range_occupied(64..128));
+}
+
+#[test]
+fn test_freeze_policies() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    bookkeeping.set_base_offset(512);
+    bookkeeping.set_reject_max_class(true);
+    bookkeeping.freeze_class(4);
+    bookkeeping.set_max_allocations(Some(3));
+    bookkeeping.set_eager_coalesce_above(64);
+    bookkeeping.set_latency_tracking(Some(4));
+
+    let named = bookkeeping
+        .alloc_with_meta(8, String::from("named"))
+        .unwrap();
+    let freed = bookkeeping.alloc(32).unwrap();
+    let _held = bookkeeping.alloc(32).unwrap();
+    // still on the channel when the copy is made
+    drop(freed);
+
+    let frozen = bookkeeping.freeze();
+    assert_eq!(frozen.base_offset(), bookkeeping.base_offset());
+    assert_eq!(frozen.eager_coalesce_above(), 64);
+    assert_eq!(frozen.stats(), bookkeeping.stats());
+    assert_eq!(
+        frozen.recent_alloc_latencies().len(),
+        bookkeeping.recent_alloc_latencies().len()
+    );
+    assert_eq!(
+        frozen.max_coalescible_free(),
+        bookkeeping.max_coalescible_free()
+    );
+    assert_eq!(
+        frozen.meta::<String>(&named).map(String::as_str),
+        Some("named")
+    );
+
+    for count in [1, 16, 100, 200, 256] {
+        assert_eq!(
+            frozen.rounded_size_for(count),
+            bookkeeping.rounded_size_for(count)
+        );
+        assert_eq!(
+            frozen.free_blocks_fitting(count),
+            bookkeeping.free_blocks_fitting(count)
+        );
+    }
+    assert!(frozen.is_class_frozen(4));
+    assert_eq!(frozen.plan(&[16]), bookkeeping.plan(&[16]));
+    assert_eq!(frozen.plan(&[8, 8]), bookkeeping.plan(&[8, 8]));
+}
+
+#[test]
+fn test_validate() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(64, 8, 64);
+    let _a = arena.alloc(64).unwrap();
+    assert_eq!(arena.validate(), Ok(()));
+
+    // the bookkeeping thinks there's more room than there is
+    let mut arena = BuddyArena {
+        elements: vec![0u8; 32].into(),
+        bookkeeping: BuddyBookkeeping::new(64, 8, 64),
+        strict_views: true,
+    };
+    let _low = arena.alloc(32).unwrap();
+    assert_eq!(arena.validate(), Ok(()));
+
+    let _high = arena.alloc(32).unwrap();
+    assert!(arena.validate().unwrap_err().contains("32..64"));
+}
+
+#[test]
+fn test_shared_allocation() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(64, 8, 64);
+
+    let a = arena.alloc(16).unwrap();
+    arena.view_mut(&a).fill(3);
+
+    let shared = a.into_shared();
+    let clones: Vec<_> = (0..3).map(|_| shared.clone()).collect();
+    drop(shared);
+
+    for clone in &clones {
+        assert_eq!(arena.view_shared(clone), &[3; 16]);
+    }
+
+    let mut clones = clones.into_iter();
+    let last = clones.next_back().unwrap();
+    drop(clones);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 16);
+    assert_eq!(arena.view_shared(&last), &[3; 16]);
+
+    drop(last);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 0);
+}
+
+#[test]
+fn test_natural_alignment() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 1024);
+
+    let first = bookkeeping.alloc(64).unwrap();
+    assert_eq!(first.natural_alignment(&bookkeeping), 1024);
+
+    let second = bookkeeping.alloc(64).unwrap();
+    assert_eq!(second.range().start % 64, 0);
+    assert_eq!(second.natural_alignment(&bookkeeping), 64);
+
+    let third = bookkeeping.alloc(64).unwrap();
+    assert_eq!(third.range().start, 128);
+    assert_eq!(third.natural_alignment(&bookkeeping), 128);
+
+    let (_, tail) = third.split_at(8);
+    assert_eq!(tail.natural_alignment(&bookkeeping), 8);
+}
+
+#[test]
+fn test_state_hash() {
+    fn run(sizes: &[usize]) -> (BuddyBookkeeping, Vec<Allocation>) {
+        let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 512);
+
+        let mut allocations: Vec<_> = sizes.iter().filter_map(|&n| bookkeeping.alloc(n)).collect();
+        allocations.retain(|a| a.range().len() % 3 != 0);
+        bookkeeping.tidy();
+
+        (bookkeeping, allocations)
+    }
+
+    let (first, _first_allocations) = run(&[5, 60, 3, 200, 33, 9]);
+    let (second, _second_allocations) = run(&[5, 60, 3, 200, 33, 9]);
+    let (different, _different_allocations) = run(&[5, 60, 3, 200, 33, 10]);
+
+    assert_eq!(first.state_hash(), second.state_hash());
+    assert_eq!(first.snapshot(), second.snapshot());
+    assert_ne!(first.state_hash(), different.state_hash());
+}
+
+#[test]
+fn test_alloc_at_most() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    let _taken = bookkeeping.alloc(128).unwrap();
+
+    let degraded = bookkeeping.alloc_at_most(200, 16).unwrap();
+    assert_eq!(degraded.range().len(), 128);
+
+    assert!(bookkeeping.alloc_at_most(200, 16).is_none());
+
+    drop(degraded);
+    bookkeeping.tidy();
+    let _quarter = bookkeeping.alloc(64).unwrap();
+    let _eighth = bookkeeping.alloc(32).unwrap();
+    // only 32 elements are left at the end
+    let at_least = bookkeeping.alloc_at_most(100, 20).unwrap();
+    assert_eq!(at_least.range(), 224..256);
+}
+
+#[test]
+fn test_reconstruct_handles() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+
+    for count in [10, 32, 64] {
+        std::mem::forget(bookkeeping.alloc(count).unwrap());
+    }
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 16 + 32 + 64);
+
+    let mut raw = bookkeeping.reconstruct_handles();
+    raw.sort_by_key(|raw| raw.range().start);
+    let ranges: Vec<_> = raw.iter().map(RawAllocation::range).collect();
+    assert_eq!(ranges, [0..10, 32..64, 64..128]);
+
+    for raw in raw {
+        bookkeeping.free_raw(raw);
+    }
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 0);
+}
+
+#[test]
+fn test_base_alignment() {
+    let mut bookkeeping = BuddyBookkeeping::new_aligned(4096, 16, 1024, 16);
+    assert_eq!(bookkeeping.base_alignment(), 16);
+    assert_eq!(
+        BuddyBookkeeping::new_aligned(4096, 16, 1024, 8).base_alignment(),
+        8
+    );
+    assert_eq!(BuddyBookkeeping::new(4096, 16, 1024).base_alignment(), 16);
+
+    let allocations: Vec<_> = [1, 3, 17, 5, 100, 2, 33]
+        .into_iter()
+        .map(|count| bookkeeping.alloc(count).unwrap())
+        .collect();
+
+    for allocation in &allocations {
+        assert_eq!(allocation.range().start % 16, 0);
+    }
+}
+
+#[test]
+fn test_free_blocks_fitting() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+
+    let mut allocations: Vec<_> = (0..8).map(|_| bookkeeping.alloc(16).unwrap()).collect();
+    // keep every other one
+    allocations.retain(|a| a.range().start % 32 == 0);
+    bookkeeping.tidy();
+
+    // 16..32, 48..64, 80..96 and 112..128 are free, plus 128..256
+    assert_eq!(bookkeeping.free_blocks_fitting(30), vec![128..256]);
+    assert_eq!(
+        bookkeeping.free_blocks_fitting(9),
+        [16..32, 48..64, 80..96, 112..128, 128..256]
+    );
+    assert!(bookkeeping.free_blocks_fitting(200).is_empty());
+}
+
+#[test]
+fn test_pinned_migrate() {
+    let mut source: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+    let mut dest: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+
+    let mut pinned = source.alloc(16).unwrap();
+    pinned.pin();
+    let movable = source.alloc(16).unwrap();
+
+    let pinned = source.migrate(&mut dest, pinned).unwrap_err();
+    assert!(pinned.is_pinned());
+    assert_eq!(pinned.range(), 0..16);
+
+    let moved = source.migrate(&mut dest, movable).unwrap();
+    assert_eq!(moved.range(), 0..16);
+    assert!(!moved.is_pinned());
+
+    source.tidy();
+    assert_eq!(source.bookkeeping().used_bytes(), 16);
+}
+
+#[test]
+fn test_alloc_split_tail() {
+    let mut bookkeeping = BuddyBookkeeping::new(64, 8, 64);
+
+    let pieces = bookkeeping.alloc_split_tail(24).unwrap();
+    let ranges: Vec<_> = pieces.iter().map(Allocation::range).collect();
+    assert_eq!(ranges, [0..16, 16..24]);
+    assert_eq!(bookkeeping.used_bytes(), 24);
+
+    // the 8 that rounding would have wasted is free for someone else
+    let tail = bookkeeping.alloc(8).unwrap();
+    assert_eq!(tail.range(), 24..32);
+
+    let rounded = bookkeeping.alloc_split_tail(30).unwrap();
+    let ranges: Vec<_> = rounded.iter().map(Allocation::range).collect();
+    assert_eq!(ranges, [32..48, 48..56, 56..62]);
+
+    drop((pieces, tail, rounded));
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 0);
+    assert_eq!(bookkeeping.stats().succeeded, 3);
+}
+
+#[test]
+fn test_alloc_split_tail_limits() {
+    let mut bookkeeping = BuddyBookkeeping::new(64, 8, 64);
+    bookkeeping.set_max_allocations(Some(1));
+
+    // the second piece would go over the limit, so the first is given back
+    assert!(bookkeeping.alloc_split_tail(24).is_none());
+    assert_eq!(bookkeeping.live_allocations(), 0);
+    assert_eq!(bookkeeping.stats().failed_out_of_space, 1);
+
+    bookkeeping.set_max_allocations(Some(0));
+    assert!(bookkeeping.alloc_split_tail(24).is_none());
+
+    bookkeeping.set_max_allocations(None);
+    bookkeeping.freeze_class(3);
+    assert!(bookkeeping.alloc_split_tail(24).is_none());
+
+    bookkeeping.thaw_class(3);
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.alloc_split_tail(24).unwrap().len(), 2);
+    assert_eq!(bookkeeping.used_bytes(), 24);
+}
+
+#[test]
+fn test_dump_report() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 16, 256);
+    let _a = bookkeeping.alloc(10).unwrap();
+    let _b = bookkeeping.alloc(64).unwrap();
+
+    let report = bookkeeping.dump_report();
+    for line in [
+        "size: 256",
+        "used bytes: 80",
+        "free bytes: 176",
+        "live allocations: 2",
+        "internal fragmentation: 6",
+        "largest free block: 128",
+        "free blocks by size:\n  16: 1\n  32: 1\n  128: 1\n",
+        "tree:\n0..256 split\n",
+    ] {
+        assert!(report.contains(line), "missing {line:?} in:\n{report}");
+    }
+}
+
+#[test]
+fn test_free_older_than() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(256, 16, 256);
+
+    let old: Vec<_> = (0..3).map(|_| arena.alloc(16).unwrap()).collect();
+    let epoch = arena.bookkeeping().epoch();
+    let new: Vec<_> = (0..2).map(|_| arena.alloc(16).unwrap()).collect();
+
+    assert_eq!(arena.bookkeeping().oldest_occupied(), Some(0..16));
+    assert_eq!(arena.free_older_than(epoch), 3);
+    assert_eq!(arena.bookkeeping().live_allocations(), 2);
+    assert_eq!(arena.bookkeeping().oldest_occupied(), Some(new[0].range()));
+
+    // the space is reused, and the old handles can't free it from under the new owners
+    let reused: Vec<_> = (0..3).map(|_| arena.alloc(16).unwrap()).collect();
+    assert_eq!(reused[0].range(), 0..16);
+    drop(old);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 5 * 16);
+    assert_eq!(arena.view(&reused[0]).len(), 16);
+}
+
+#[test]
+fn test_builder() {
+    let mut arena = BuddyArenaBuilder::<u8>::new()
+        .size(1024)
+        .min_block_size(16)
+        .max_block_size(256)
+        .node_capacity(64)
+        .build()
+        .unwrap();
+
+    assert!(arena
+        .bookkeeping()
+        .size_classes()
+        .eq([16, 32, 64, 128, 256]));
+    assert!(arena.bookkeeping().bookkeeping_bytes() > 0);
+    let a = arena.alloc(3).unwrap();
+    assert_eq!(a.range(), 0..3);
+    assert!(arena.alloc(512).is_none());
+
+    let mut defaults = BuddyArenaBuilder::<u8>::new().size(64).build().unwrap();
+    assert!(defaults
+        .bookkeeping()
+        .size_classes()
+        .eq([1, 2, 4, 8, 16, 32, 64]));
+    assert_eq!(defaults.alloc(3).unwrap().range(), 0..3);
+}
+
+#[test]
+fn test_single_threaded() {
+    let mut bookkeeping = BuddyBookkeeping::new_single_threaded(2048, 8, 256);
+    assert!(bookkeeping.reclaim_sender().is_none());
+
+    let a = bookkeeping.alloc(64).unwrap();
+    let b = bookkeeping.alloc(8).unwrap();
+    drop((a, b));
+    assert_eq!(bookkeeping.live_allocations(), 2);
+
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 0);
+    assert_eq!(bookkeeping.live_allocations(), 0);
+
+    // dropped on another thread, where there's no queue, so the block is leaked
+    let c = bookkeeping.alloc(8).unwrap();
+    std::thread::spawn(move || drop(c)).join().unwrap();
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.live_allocations(), 1);
+
+    let mut arena = BuddyArenaBuilder::<u8>::new()
+        .size(256)
+        .single_threaded(true)
+        .build()
+        .unwrap();
+    drop(arena.alloc(16).unwrap());
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 0);
+}
+
+#[test]
+fn test_builder_errors() {
+    let build = |builder: BuddyArenaBuilder<u8>| builder.build().err();
+
+    assert_eq!(
+        build(BuddyArenaBuilder::new()),
+        Some(BuildError::MissingSize)
+    );
+    assert_eq!(
+        build(BuddyArenaBuilder::new().size(100)),
+        Some(BuildError::NotPowerOfTwo(100))
+    );
+    assert_eq!(
+        build(
+            BuddyArenaBuilder::new()
+                .size(256)
+                .min_block_size(64)
+                .max_block_size(32)
+        ),
+        Some(BuildError::MinAboveMax)
+    );
+    assert_eq!(
+        build(BuddyArenaBuilder::new().size(256).max_block_size(512)),
+        Some(BuildError::MaxAboveSize)
+    );
+}
+
+#[test]
+fn test_neighbors() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 16, 256);
+
+    let first = bookkeeping.alloc(16).unwrap();
+    let middle = bookkeeping.alloc(16).unwrap();
+    let last = bookkeeping.alloc(16).unwrap();
+
+    assert_eq!(
+        bookkeeping.neighbors(&middle),
+        (Some(first.range()), Some(last.range()))
+    );
+    assert_eq!(bookkeeping.neighbors(&first), (None, Some(middle.range())));
+    // after the last one comes the free rest of its 32 block
+    assert_eq!(
+        bookkeeping.neighbors(&last),
+        (Some(middle.range()), Some(48..64))
+    );
+}
+
+#[test]
+fn test_collapse() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 1024);
+
+    let allocations: Vec<_> = (0..40)
+        .map(|i| bookkeeping.alloc(1 + i % 20).unwrap())
+        .collect();
+    assert!(bookkeeping.snapshot().len() > 40);
+
+    bookkeeping.collapse();
+    assert_eq!(bookkeeping.snapshot(), vec![(0, 0..1024, 0)]);
+    assert_eq!(bookkeeping.used_bytes(), 0);
+    assert_eq!(bookkeeping.live_allocations(), 0);
+
+    // the old handles don't free anything in the new tree
+    let whole = bookkeeping.alloc(1024).unwrap();
+    drop(allocations);
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.used_bytes(), 1024);
+    assert_eq!(whole.range(), 0..1024);
+}
+
+#[test]
+fn test_alloc_class() {
+    let mut bookkeeping = BuddyBookkeeping::new(1024, 8, 256);
+
+    let a = bookkeeping.alloc_class(5).unwrap();
+    assert_eq!(a.range(), 0..32);
+    assert_eq!(a.size_class(&bookkeeping), 5);
+
+    assert!(bookkeeping.alloc_class(2).is_none());
+    assert!(bookkeeping.alloc_class(9).is_none());
+    assert!(bookkeeping.alloc_class(200).is_none());
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn test_view_bytes() {
+    let mut arena: BuddyArena<u32> = BuddyArena::new(64, 8, 64);
+
+    let a = arena.alloc(2).unwrap();
+    arena
+        .view_mut(&a)
+        .copy_from_slice(&[0x0102_0304, 0xaabb_ccdd]);
+
+    let bytes = arena.view_bytes(&a);
+    assert_eq!(bytes.len(), 8);
+    let expected: Vec<u8> = [0x0102_0304u32, 0xaabb_ccdd]
+        .iter()
+        .flat_map(|x| x.to_ne_bytes())
+        .collect();
+    assert_eq!(bytes, expected);
+
+    arena.view_bytes_mut(&a)[..4].copy_from_slice(&7u32.to_ne_bytes());
+    assert_eq!(arena.view(&a)[0], 7);
+}
+
+#[test]
+fn test_consolidate() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(256, 32, 256);
+
+    let a = arena.alloc(20).unwrap();
+    let b = arena.alloc(10).unwrap();
+    let c = arena.alloc(24).unwrap();
+    let d = arena.alloc(24).unwrap();
+    arena.view_mut(&a).fill(1);
+    arena.view_mut(&b).fill(2);
+    let (b_range, c_range) = (b.range(), c.range());
+
+    let mut moves = Vec::new();
+    let left = arena.consolidate(vec![a, b, c, d], |old, new| moves.push((old, new)));
+
+    // a and b share a block now, but two 24s don't fit in one 32
+    assert_eq!(left.len(), 3);
+    assert_eq!(moves, [(b_range.clone(), 20..30)]);
+    let merged = left.iter().find(|x| x.range().start == 0).unwrap();
+    assert_eq!(merged.range(), 0..30);
+    assert_eq!(&arena.view(merged)[..20], &[1; 20]);
+    assert_eq!(&arena.view(merged)[20..], &[2; 10]);
+    assert!(left.iter().any(|x| x.range() == c_range));
+
+    arena.tidy();
+    assert!(!arena.bookkeeping().is_range_occupied(b_range));
+}
+
+#[test]
+fn test_consolidate_order() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(512, 32, 512);
+
+    let big_small = arena.alloc_aligned(20, 64).unwrap();
+    let a = arena.alloc(20).unwrap();
+    let big = arena.alloc(40).unwrap();
+    let pinned = {
+        let mut pinned = arena.alloc(8).unwrap();
+        pinned.pin();
+        pinned
+    };
+    let b = arena.alloc(10).unwrap();
+    let ranges = [
+        big_small.range(),
+        a.range(),
+        big.range(),
+        pinned.range(),
+        b.range(),
+    ];
+
+    let mut moves = Vec::new();
+    let left = arena.consolidate(vec![big_small, a, big, pinned, b], |old, new| {
+        moves.push((old, new))
+    });
+
+    // the 32 blocks are packed before the 64 ones, and the survivors keep their places
+    let packed_a = ranges[1].start..(ranges[1].start + 30);
+    let packed_big = ranges[2].start..(ranges[2].start + 60);
+    assert_eq!(
+        moves,
+        [
+            (ranges[4].clone(), (ranges[1].start + 20)..packed_a.end),
+            (ranges[0].clone(), (ranges[2].start + 40)..packed_big.end),
+        ]
+    );
+
+    let left: Vec<_> = left.iter().map(Allocation::range).collect();
+    assert_eq!(left, [packed_a, packed_big, ranges[3].clone()]);
+}
+
+#[test]
+fn test_base_offset() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+    arena.bookkeeping_mut().set_base_offset(0x4000);
+
+    let a = arena.alloc(16).unwrap();
+    let b = arena.alloc(40).unwrap();
+    assert_eq!(a.range(), 0x4000..0x4010);
+    assert_eq!(b.range(), 0x4040..0x4068);
+
+    // views still index the element buffer from 0
+    arena.view_mut(&b).fill(9);
+    assert_eq!(arena.view(&b).len(), 40);
+    assert!(arena.bookkeeping().is_range_occupied(64..104));
+
+    let (_, tail) = b.split_at(8);
+    assert_eq!(tail.range(), 0x4048..0x4068);
+}
+
+#[test]
+fn test_base_offset_alignment() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(1024, 8, 256);
+    arena.bookkeeping_mut().set_base_offset(256);
+
+    let _a = arena.alloc(8).unwrap();
+    let aligned = arena.alloc_aligned_const::<64>(10).unwrap();
+    assert_eq!(aligned.range().start % 64, 0);
+
+    // at 256 in the tree, but it's the shifted start that counts
+    let b = arena.alloc(256).unwrap();
+    assert_eq!(b.range(), 512..768);
+    assert_eq!(b.natural_alignment(arena.bookkeeping()), 512);
+}
+
+#[test]
+fn test_base_offset_near_max() {
+    // the highest aligned region of 512 whose end still fits in a usize
+    let base = usize::MAX - 1023;
+    let mut bookkeeping = BuddyBookkeeping::new_region(base, 512, 8, 256);
+
+    let _a = bookkeeping.alloc(8).unwrap();
+    let b = bookkeeping.alloc(256).unwrap();
+    assert_eq!(b.range(), (base + 256)..(base + 512));
+    assert_eq!(b.range().end, usize::MAX - 511);
+}
+
+#[test]
+#[should_panic(expected = "past usize::MAX")]
+fn test_base_offset_overflow() {
+    BuddyBookkeeping::new_region(usize::MAX - 255, 256, 8, 256);
+}
+
+#[test]
+fn test_base_offset_unaligned() {
+    let mut bookkeeping = BuddyBookkeeping::new(1 << 20, 16, 1 << 20);
+    bookkeeping.set_base_offset(0x4000);
+
+    let a = bookkeeping.alloc(16).unwrap();
+    assert_eq!(a.range(), 0x4000..0x4010);
+    assert_eq!(a.natural_alignment(&bookkeeping), 0x4000);
+
+    // 0x8000-aligned starts are at 0x4000 within each 0x8000 of the tree, where only
+    // blocks of at most 0x4000 can start
+    let b = bookkeeping.alloc_aligned(100, 0x8000).unwrap();
+    assert_eq!(b.range().start, 0x8000);
+    assert_eq!(b.natural_alignment(&bookkeeping), 0x8000);
+    assert_eq!(
+        bookkeeping
+            .alloc_aligned(0x4000, 0x8000)
+            .unwrap()
+            .range()
+            .start,
+        0x10000
+    );
+    assert!(bookkeeping.alloc_aligned(0x4001, 0x8000).is_none());
+
+    let mut odd = BuddyBookkeeping::new(1024, 4, 256);
+    odd.set_base_offset(4);
+    assert_eq!(odd.alloc(8).unwrap().range(), 4..12);
+    assert_eq!(odd.alloc_aligned(3, 8).unwrap().range(), 16..19);
+    // only blocks of at most 4 can start at a multiple of 8
+    assert!(odd.alloc_aligned(8, 8).is_none());
+}
+
+#[test]
+fn test_stats() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 16, 128);
+
+    let a = bookkeeping.alloc(16).unwrap();
+    let b = bookkeeping.alloc(16).unwrap();
+    let _c = bookkeeping.alloc(128).unwrap();
+    assert!(bookkeeping.alloc(200).is_none());
+    assert!(bookkeeping.alloc(0).is_none());
+    assert!(bookkeeping.alloc(128).is_none());
+
+    drop((a, b));
+    bookkeeping.tidy();
+
+    // 16 + 16 -> 32, 32 + 32 -> 64, 64 + 64 -> 128
+    assert_eq!(
+        bookkeeping.stats(),
+        AllocStats {
+            attempted: 6,
+            succeeded: 3,
+            failed_too_large: 2,
+            failed_out_of_space: 1,
+            tidies: 1,
+            merges: 3,
+        }
+    );
+
+    bookkeeping.reset_stats();
+    assert_eq!(bookkeeping.stats(), AllocStats::default());
+}
+
+#[test]
+fn test_free_runs() {
+    let mut bookkeeping = BuddyBookkeeping::new(128, 16, 128);
+
+    let _a = bookkeeping.alloc(16).unwrap();
+    let b = bookkeeping.alloc(16).unwrap();
+    let c = bookkeeping.alloc(32).unwrap();
+    let _d = bookkeeping.alloc(64).unwrap();
+    assert_eq!(bookkeeping.free_runs().count(), 0);
+
+    // 16..32 and 32..64 have different parents, but sit next to each other
+    drop((b, c));
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.free_runs().collect::<Vec<_>>(), vec![16..64]);
+}
+
+#[test]
+fn test_check_alignment_for() {
+    let bytes: BuddyArena<u8> = BuddyArena::new(256, 1, 256);
+    assert!(bytes.check_alignment_for::<u8>().is_ok());
+    assert_eq!(
+        bytes.check_alignment_for::<u64>(),
+        Err(MisalignedError {
+            required: 8,
+            guaranteed: 1
+        })
+    );
+
+    let words: BuddyArena<u32> = BuddyArena::new(256, 2, 256);
+    assert!(words.check_alignment_for::<u64>().is_ok());
+
+    // a u8 arena can only become a u64 arena if every block starts 8 bytes apart, and then
+    // the new buffer is aligned for u64s too
+    let halves: BuddyArena<u8> = BuddyArena::new(256, 4, 256);
+    assert!(unsafe { halves.transmute_elements::<u64>() }.is_none());
+
+    let blocks: BuddyArena<u8> = BuddyArena::new(256, 8, 256);
+    let blocks: BuddyArena<u64> = unsafe { blocks.transmute_elements() }.unwrap();
+    assert!(blocks.check_alignment_for::<u64>().is_ok());
+}
+
+#[test]
+fn test_tidy_progress() {
+    let mut bk = BuddyBookkeeping::new(64, 8, 64);
+    assert!(!bk.tidy_progress());
+
+    let a = bk.alloc(8).unwrap();
+    let b = bk.alloc(8).unwrap();
+    assert!(!bk.tidy_progress());
+
+    drop(a);
+    assert!(bk.tidy_progress());
+    assert!(!bk.tidy_progress());
+
+    drop(b);
+    assert!(bk.tidy_progress());
+    assert!(!bk.tidy_progress());
+    assert_eq!(bk.used_bytes(), 0);
+}
+
+#[test]
+fn test_scope() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    let outside = bk.alloc(64).unwrap();
+
+    {
+        let mut scope = bk.scope();
+        let ranges: Vec<_> = (0..6).map(|_| scope.alloc(16).unwrap()).collect();
+        assert_eq!(scope.len(), 6);
+        assert_eq!(scope.bookkeeping().used_bytes(), 64 + 6 * 16);
+        assert!(ranges.windows(2).all(|pair| pair[0].end <= pair[1].start));
+    }
+
+    assert_eq!(bk.used_bytes(), 64);
+    bk.tidy();
+    assert!(bk.alloc(128).is_some());
+    drop(outside);
+}
+
+#[test]
+fn test_eager_coalesce_above() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    bk.set_eager_coalesce_above(64);
+
+    let first = bk.alloc(64).unwrap();
+    let second = bk.alloc(64).unwrap();
+    let small = bk.alloc(8).unwrap();
+    let nodes = bk.blocks.len();
+
+    drop(first);
+    bk.tidy_gas(1);
+    assert_eq!(bk.blocks.len(), nodes);
+
+    // merged with its freed buddy without a tidy
+    drop(second);
+    bk.tidy_gas(1);
+    assert_eq!(bk.blocks.len(), nodes - 2);
+
+    drop(small);
+    bk.tidy_gas(1);
+    assert_eq!(bk.blocks.len(), nodes - 2);
+
+    bk.tidy();
+    assert_eq!(bk.blocks.len(), 1);
+}
+
+#[test]
+fn test_bookkeeping_eq() {
+    let mut a = BuddyBookkeeping::new(256, 8, 256);
+    let mut b = BuddyBookkeeping::new(256, 8, 256);
+    assert!(a == b);
+
+    // the same allocations, but b takes a detour through an extra allocation
+    let _a1 = a.alloc(16).unwrap();
+    let _a2 = a.alloc(64).unwrap();
+
+    let _b1 = b.alloc(16).unwrap();
+    let detour = b.alloc(128).unwrap();
+    let _b2 = b.alloc(64).unwrap();
+    assert!(a != b);
+
+    drop(detour);
+    b.tidy();
+    assert!(a == b);
+
+    let _a3 = a.alloc(8).unwrap();
+    assert!(a != b);
+}
+
+#[test]
+fn test_new_region() {
+    let base = 0x10_0000;
+    let mut bk = BuddyBookkeeping::new_region(base, 1024, 16, 256);
+    assert_eq!(bk.base_offset(), base);
+
+    let allocations: Vec<_> = std::iter::from_fn(|| bk.alloc(100)).collect();
+    assert_eq!(allocations.len(), 8);
+
+    for a in &allocations {
+        assert!(a.range().start >= base && a.range().end <= base + 1024);
+    }
+}
+
+#[test]
+fn test_new_region_uneven() {
+    // 3 MiB and a bit, at an offset that isn't aligned to anything big
+    let (base, size) = (0x4010, (3 << 20) + 40);
+    let region = base..base + size;
+    let mut bk = BuddyBookkeeping::new_region(base, size, 16, 1 << 20);
+    let tail = (4 << 20) - (3 << 20) - 32;
+    assert_eq!(bk.used_bytes(), tail);
+
+    let mut allocations = Vec::new();
+    for count in [1 << 20, 4096, 100, 16] {
+        allocations.extend(std::iter::from_fn(|| bk.alloc(count)));
+    }
+
+    // the 40 extra are rounded down to two more 16 blocks
+    let lens: Vec<_> = allocations.iter().map(|a| a.range().len()).collect();
+    assert_eq!(lens, [1 << 20, 1 << 20, 1 << 20, 16, 16]);
+    for a in &allocations {
+        assert!(region.start <= a.range().start && a.range().end <= region.end);
+    }
+    assert_eq!(allocations[4].range().end, base + (3 << 20) + 32);
+    assert_eq!(bk.reconstruct_handles().len(), 5);
+
+    let checkpoint = bk.checkpoint();
+    drop(allocations);
+    bk.tidy();
+    assert_eq!(bk.used_bytes(), tail);
+    bk.shrink_bookkeeping();
+    bk.rewind(checkpoint);
+    assert_eq!(bk.used_bytes(), tail);
+
+    // the tail is still there after a reset
+    bk.reset();
+    assert_eq!(bk.used_bytes(), tail);
+    let big: Vec<_> = std::iter::from_fn(|| bk.alloc(1 << 20)).collect();
+    assert_eq!(big.len(), 3);
+}
+
+#[test]
+fn test_reject_max_class() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 128);
+    bk.set_reject_max_class(true);
+
+    assert!(bk.alloc(128).is_none());
+    assert!(bk.alloc(65).is_none());
+    assert_eq!(bk.stats().failed_too_large, 2);
+
+    let half = bk.alloc(64).unwrap();
+    assert_eq!(half.range().len(), 64);
+
+    bk.set_reject_max_class(false);
+    assert!(bk.alloc(128).is_some());
+}
+
+#[test]
+fn test_reject_max_class_everywhere() {
+    let mut bk = BuddyBookkeeping::new(1024, 8, 256);
+    bk.set_reject_max_class(true);
+
+    assert!(bk.alloc_aligned(8, 256).is_none());
+    assert!(bk.alloc_exact(256).is_none());
+    assert!(bk.alloc_class(8).is_none());
+    assert!(!bk.plan(&[256]));
+
+    assert_eq!(bk.alloc_exact(128).unwrap().range().len(), 128);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "allocation is stale")]
+fn test_realloc_view_checked() {
+    let mut arena: BuddyArena<u32> = BuddyArena::new(256, 8, 256);
+    let a = arena.alloc(10).unwrap();
+    arena.view_mut(&a).copy_from_slice(&[7; 10]);
+
+    // a copy of the handle's identity, like a range copied out before reallocating
+    let stale = a.detached_copy();
+
+    // grows in place, since the block is 16 long
+    let a = arena.realloc(a, 16).unwrap();
+    assert_eq!(a.index, stale.index);
+    assert_eq!(&arena.view_checked(&a)[..10], &[7; 10]);
+
+    let a = arena.realloc(a, 100).unwrap();
+    assert_ne!(a.index, stale.index);
+    assert_eq!(arena.view_checked(&a).len(), 100);
+    assert_eq!(&arena.view(&a)[..10], &[7; 10]);
+
+    assert!(arena.realloc(a, 512).is_err());
+    arena.view_checked(&stale);
+}
+
+#[test]
+fn test_try_walk() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    let _a = bk.alloc(64).unwrap();
+    let _b = bk.alloc(16).unwrap();
+
+    let mut visited = 0;
+    let first_occupied = bk.try_walk(|range, kind| {
+        visited += 1;
+
+        match kind {
+            BlockKind::Occupied => ControlFlow::Break(range.clone()),
+            _ => ControlFlow::Continue(()),
+        }
+    });
+    assert_eq!(first_occupied, Some(0..64));
+    // the root, its first half, and that half's first half
+    assert_eq!(visited, 3);
+
+    let mut visited = 0;
+    let large = bk.try_walk(|range, kind| {
+        visited += 1;
+
+        if kind == BlockKind::Occupied && range.len() > 64 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    assert_eq!(large, None);
+    assert_eq!(visited, bk.snapshot().len());
+}
+
+#[test]
+fn test_alloc_padded() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(64, 8, 64);
+
+    // leave garbage behind where the padding will go
+    let dirty = arena.alloc(32).unwrap();
+    arena.view_mut(&dirty).fill(0xff);
+    drop(dirty);
+    arena.tidy();
+
+    let a = arena.alloc_padded(20).unwrap();
+    assert_eq!(a.range().len(), 32);
+    assert_eq!(a.logical_len(), 20);
+    assert!(arena.view(&a)[20..].iter().all(|&x| x == 0));
+    assert_eq!(arena.bookkeeping().internal_fragmentation(), 12);
+
+    let (data, padding) = a.split_at(20);
+    assert_eq!((data.logical_len(), padding.logical_len()), (20, 0));
+
+    let plain = arena.alloc(20).unwrap();
+    assert_eq!(plain.logical_len(), 20);
+}
+
+#[test]
+fn test_reclaim_hub() {
+    let mut hub = ReclaimHub::new();
+    let mut first = hub.bookkeeping(64, 8, 64);
+    let mut second = hub.bookkeeping(64, 8, 64);
+    assert_eq!((first.arena_id(), second.arena_id()), (Some(0), Some(1)));
+    assert!(first.reclaim_sender().is_none());
+
+    let a = first.alloc(32).unwrap();
+    let b = second.alloc(64).unwrap();
+    assert!(second.alloc(8).is_none());
+
+    // moving to other threads doesn't matter, the frees all go to the hub
+    std::thread::spawn(move || drop((a, b))).join().unwrap();
+
+    // the bookkeepings' own tidies don't see them
+    first.tidy();
+    assert_eq!(first.used_bytes(), 32);
+
+    hub.tidy(&mut [&mut first, &mut second]);
+    assert_eq!((first.used_bytes(), second.used_bytes()), (0, 0));
+    assert!(second.alloc(64).is_some());
+}
+
+#[test]
+fn test_reclaim_from() {
+    let mut bk = BuddyBookkeeping::new(64, 8, 64);
+    let sender = bk.reclaim_sender().unwrap();
+
+    let a = bk.alloc(16).unwrap();
+    let index = a.disown().unwrap();
+    sender.send(index).unwrap();
+
+    bk.reclaim_from(&[index]);
+    assert_eq!(bk.used_bytes(), 0);
+
+    // the copy that went through the channel is ignored
+    bk.tidy();
+    assert_eq!(bk.used_bytes(), 0);
+    assert_eq!(bk.live_allocations(), 0);
+}
+
+#[test]
+fn test_alloc_keeping_reserve() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    let _a = bk.alloc_keeping_reserve(64, 128).unwrap();
+
+    // a second 64 still leaves the other half free
+    let b = bk.alloc_keeping_reserve(64, 128).unwrap();
+
+    // but anything more would have to split it
+    assert!(bk.alloc_keeping_reserve(8, 128).is_none());
+    assert_eq!(bk.stats().failed_out_of_space, 1);
+    assert!(bk.alloc_keeping_reserve(8, 64).is_some());
+
+    drop(b);
+    bk.tidy();
+    assert!(bk.alloc_keeping_reserve(32, 128).is_some());
+}
+
+#[test]
+fn test_alloc_keeping_reserve_limits() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    bk.set_recycle_capacity(64, 1);
+    bk.freeze_class(5);
+
+    // the class is frozen, so it fails like `alloc` would, even with room to spare
+    assert!(bk.alloc_keeping_reserve(32, 8).is_none());
+    assert_eq!(bk.stats().failed_out_of_space, 1);
+
+    let a = bk.alloc_keeping_reserve(64, 128).unwrap();
+    drop(a);
+    bk.tidy();
+
+    // the recycled block is reused, so the tree's free half is left alone
+    assert_eq!(bk.alloc_keeping_reserve(64, 128).unwrap().range(), 0..64);
+
+    bk.set_max_allocations(Some(1));
+    assert!(bk.alloc_keeping_reserve(8, 8).is_none());
+}
+
+#[test]
+fn test_freeze_class() {
+    let mut bk = BuddyBookkeeping::new(1024, 8, 1024);
+    bk.freeze_class(6);
+    assert!(bk.is_class_frozen(6));
+
+    assert!(bk.alloc(64).is_none());
+    assert!(bk.alloc(40).is_none());
+    assert_eq!(bk.alloc(32).unwrap().range().len(), 32);
+    assert_eq!(bk.alloc(128).unwrap().range().len(), 128);
+
+    assert!(!bk.plan(&[32, 64]));
+    assert!(bk.plan(&[32, 128]));
+
+    bk.thaw_class(6);
+    assert!(bk.plan(&[32, 64]));
+    assert!(bk.alloc(64).is_some());
+
+    // too big to be a block size, so there's nothing to freeze
+    bk.freeze_class(64);
+    bk.thaw_class(200);
+    assert!(!bk.is_class_frozen(64));
+}
+
+#[test]
+fn test_block_indices() {
+    let mut bk = BuddyBookkeeping::new(256, 8, 256);
+    let a = bk.alloc(8).unwrap();
+    let _b = bk.alloc(64).unwrap();
+
+    let indices: Vec<Index> = bk.block_indices().collect();
+    assert_eq!(indices.len(), bk.snapshot().len());
+    assert!(indices.iter().all(|&index| bk.blocks.contains(index)));
+    assert!(indices.contains(&a.index));
+    assert!(indices.contains(&bk.root));
+}
+
+#[test]
+fn test_alloc_largest() {
+    let mut bk = BuddyBookkeeping::new(1024, 8, 256);
+
+    // capped at the biggest block size
+    let capped = bk.alloc_largest().unwrap();
+    assert_eq!(capped.range(), 0..256);
+
+    let _a = bk.alloc(128).unwrap();
+    let _b = bk.alloc(8).unwrap();
+    drop(capped);
+    bk.tidy();
+
+    // 512..1024 is the biggest, but capped to 256 the free block at the start fits exactly
+    assert_eq!(bk.alloc_largest().unwrap().range(), 0..256);
+    assert_eq!(bk.alloc_largest().unwrap().range(), 512..768);
+    assert_eq!(bk.alloc_largest().unwrap().range(), 768..1024);
+    assert_eq!(bk.alloc_largest().unwrap().range(), 448..512);
+
+    let mut full = BuddyBookkeeping::new(64, 8, 64);
+    let _all = full.alloc(64).unwrap();
+    assert!(full.alloc_largest().is_none());
+}
+
+#[test]
+fn test_alloc_largest_policies() {
+    let mut bk = BuddyBookkeeping::new(1024, 8, 256);
+    bk.freeze_class(8);
+    assert_eq!(bk.alloc_largest().unwrap().range().len(), 128);
+
+    bk.thaw_class(8);
+    bk.set_reject_max_class(true);
+    assert_eq!(bk.alloc_largest().unwrap().range().len(), 128);
+
+    bk.freeze_class(7);
+    assert_eq!(bk.alloc_largest().unwrap().range().len(), 64);
+}
+
+#[test]
+fn test_recycled_blocks_not_counted() {
+    let mut bookkeeping = BuddyBookkeeping::new(256, 8, 256);
+    bookkeeping.set_recycle_capacity(32, 4);
+
+    drop(bookkeeping.alloc(20).unwrap());
+    bookkeeping.tidy();
+    assert_eq!(bookkeeping.live_allocations(), 0);
+
+    assert_eq!(bookkeeping.internal_fragmentation(), 0);
+    assert_eq!(bookkeeping.alignment_overhead(64), 0);
+
+    let reused = bookkeeping.alloc(20).unwrap();
+    assert_eq!(bookkeeping.internal_fragmentation(), 12);
+    assert_eq!(bookkeeping.alignment_overhead(64), 32);
+    drop(reused);
+}
+
+#[test]
+fn test_try_view_restamped() {
+    let mut arena: BuddyArena<u32> = BuddyArena::new(256, 8, 256);
+    let a = arena.alloc(10).unwrap();
+
+    let stale = a.detached_copy();
+    assert!(arena.try_view(&stale).is_ok());
+
+    // same block, new serial
+    let a = arena.realloc(a, 12).unwrap();
+    assert_eq!(arena.try_view(&stale), Err(ViewError::StaleIndex));
+    assert_eq!(
+        arena.try_view_mut(&stale).err(),
+        Some(ViewError::StaleIndex)
+    );
+    assert!(arena.try_view(&a).is_ok());
+}
+
+#[test]
+fn test_recycled_handle_is_stale() {
+    let mut arena: BuddyArena<u32> = BuddyArena::new(256, 8, 256);
+    arena.bookkeeping_mut().set_recycle_capacity(32, 1);
+
+    let a = arena.alloc(20).unwrap();
+    let weak = a.downgrade();
+    let stale = a.detached_copy();
+
+    drop(a);
+    arena.tidy();
+    assert!(arena.bookkeeping().is_occupied(stale.index));
+
+    assert_eq!(arena.try_view(&stale), Err(ViewError::StaleIndex));
+    assert!(arena.upgrade_view(&weak).is_none());
+
+    // reusing the block hands out a new serial, which the old handles still don't match
+    let b = arena.alloc(20).unwrap();
+    assert_eq!(b.index, stale.index);
+    assert_eq!(arena.try_view(&stale), Err(ViewError::StaleIndex));
+    assert!(arena.upgrade_view(&weak).is_none());
+    assert!(arena.try_view(&b).is_ok());
 }
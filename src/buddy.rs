@@ -8,19 +8,63 @@ use std::{cmp::Ordering, ops::Range, time::Instant};
 
 use generational_arena::{Arena, Index};
 
-pub(crate) fn is_pow_of_two(x: usize) -> bool {
+use crate::arena::BuddyBookkeeping;
+
+pub(crate) const fn is_pow_of_two(x: usize) -> bool {
     (x != 0) && ((x & (x - 1)) == 0)
 }
 
+/// A size that's known to be a power of two
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pow2(usize);
+
+impl Pow2 {
+    pub const fn new(x: usize) -> Option<Pow2> {
+        if is_pow_of_two(x) {
+            Some(Pow2(x))
+        } else {
+            None
+        }
+    }
+
+    /// Panics if `x` isn't a power of two, which is a compile error in const contexts
+    pub const fn new_const(x: usize) -> Pow2 {
+        assert!(is_pow_of_two(x), "not a power of two");
+
+        Pow2(x)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Clone)]
 pub(crate) enum BlockState {
     Split(Index, Index),
     Available,
     Occupied,
 }
 
+#[derive(Clone)]
 pub struct Block {
     pub(crate) range: Range<usize>,
     pub(crate) state: BlockState,
+    /// How many elements were asked for when this block was last occupied
+    pub(crate) requested: usize,
+    /// Serial number of the allocation that last occupied this block
+    pub(crate) serial: u64,
+}
+
+impl Block {
+    pub(crate) fn available(range: Range<usize>) -> Block {
+        Block {
+            range,
+            state: BlockState::Available,
+            requested: 0,
+            serial: 0,
+        }
+    }
 }
 
 /// Assumes `desired_size` is a power of 2
@@ -43,43 +87,226 @@ pub fn alloc(arena: &mut Arena<Block>, block_index: Index, desired_size: usize)
         Ordering::Greater => match block.state {
             BlockState::Occupied => None,
             BlockState::Available => {
-                let first_range = (block.range.start)..(block.range.start + block.range.len() / 2);
-                let second_range = (block.range.start + block.range.len() / 2)..(block.range.end);
-
-                let first = arena.insert(Block {
-                    range: first_range,
-                    state: BlockState::Available,
-                });
-
-                let second = arena.insert(Block {
-                    range: second_range,
-                    state: BlockState::Available,
-                });
-
-                arena[block_index].state = BlockState::Split(first, second);
+                let (first, _) = split(arena, block_index)?;
 
                 alloc(arena, first, desired_size)
             }
-            BlockState::Split(first_index, second_index) => {
-                if let Some(result) = alloc(arena, first_index, desired_size) {
-                    Some(result)
-                } else if let Some(result) = alloc(arena, second_index, desired_size) {
-                    Some(result)
-                } else {
-                    None
-                }
-            }
+            BlockState::Split(first_index, second_index) => alloc(arena, first_index, desired_size)
+                .or_else(|| alloc(arena, second_index, desired_size)),
         },
     }
 }
 
+/// Like `alloc`, but only hands out a block if `fits` accepts its range. `fits` is also asked
+/// about bigger blocks on the way down, and should return whether they could contain a block
+/// it would accept.
+pub fn alloc_where(
+    arena: &mut Arena<Block>,
+    block_index: Index,
+    desired_size: usize,
+    fits: &impl Fn(&Range<usize>) -> bool,
+) -> Option<Index> {
+    debug_assert!(is_pow_of_two(desired_size));
+
+    let block = &arena[block_index];
+
+    if block.range.len() < desired_size || !fits(&block.range) {
+        return None;
+    }
+
+    match block.state {
+        BlockState::Occupied => None,
+        BlockState::Available if block.range.len() == desired_size => {
+            arena[block_index].state = BlockState::Occupied;
+
+            Some(block_index)
+        }
+        BlockState::Available => {
+            let (first, second) = split(arena, block_index)?;
+
+            alloc_where(arena, first, desired_size, fits)
+                .or_else(|| alloc_where(arena, second, desired_size, fits))
+        }
+        BlockState::Split(first, second) => alloc_where(arena, first, desired_size, fits)
+            .or_else(|| alloc_where(arena, second, desired_size, fits)),
+    }
+}
+
+/// Splits an available block into two available halves, returning their indices. `None` if
+/// the block isn't available or is too small to split; keeping above the minimum block size
+/// is up to the caller.
+pub fn split(arena: &mut Arena<Block>, block_index: Index) -> Option<(Index, Index)> {
+    let block = &arena[block_index];
+
+    if !matches!(block.state, BlockState::Available) || block.range.len() < 2 {
+        return None;
+    }
+
+    // can't overflow for a well-formed range, but fail rather than wrap if it does
+    let mid = block.range.start.checked_add(block.range.len() / 2)?;
+    let (first_range, second_range) = (block.range.start..mid, mid..block.range.end);
+
+    let first = arena.insert(Block::available(first_range));
+    let second = arena.insert(Block::available(second_range));
+
+    arena[block_index].state = BlockState::Split(first, second);
+
+    Some((first, second))
+}
+
+/// Suggests a `min_block_size` for an arena of `size` given a sample of allocation sizes: the
+/// power of two covering the smallest quarter of them. Going any smaller mostly adds tree
+/// depth for the few tiny allocations, while going bigger rounds up most of the small ones.
+pub fn suggest_min_block_size(sizes: &[usize], size: usize) -> usize {
+    let mut sorted = sizes.to_vec();
+    sorted.sort_unstable();
+
+    let Some(&quartile) = sorted.get(sorted.len() / 4) else {
+        return 1;
+    };
+
+    quartile
+        .max(1)
+        .checked_next_power_of_two()
+        .map_or(size, |min| min.min(size))
+}
+
+/// The smallest arena that `sizes` could all be allocated from at once, in order, found by
+/// planning them into bigger and bigger arenas. Rounding each one up to a block can put this
+/// well over the sum of the sizes. Packing the blocks into a fresh tree doesn't lose anything
+/// more, since every free block left over is a different size, so this is the rounded total
+/// made a power of two (and at least `max_block_size`). `None` if any of them is empty or
+/// bigger than `max_block_size`.
+pub fn min_arena_size(
+    sizes: &[usize],
+    min_block_size: usize,
+    max_block_size: usize,
+) -> Option<usize> {
+    let mut rounded_total: usize = 0;
+    for &count in sizes {
+        if count == 0 || count > max_block_size {
+            return None;
+        }
+
+        let rounded = count.next_power_of_two().max(min_block_size);
+        rounded_total = rounded_total.checked_add(rounded)?;
+    }
+
+    // every allocation in its own biggest block always fits
+    let upper = max_block_size
+        .checked_mul(sizes.len().max(1))?
+        .checked_next_power_of_two()?;
+    let mut size = rounded_total
+        .checked_next_power_of_two()?
+        .max(max_block_size);
+
+    while size <= upper {
+        if BuddyBookkeeping::new(size, min_block_size, max_block_size).plan(sizes) {
+            return Some(size);
+        }
+
+        size = size.checked_mul(2)?;
+    }
+
+    None
+}
+
+/// Runs a random workload of `iterations` steps on a new arena, each step either allocating
+/// one of `sizes` (picked uniformly) or freeing a random live allocation, and returns the
+/// average fragmentation after each step. Fragmentation is the fraction of the arena that's
+/// neither in use nor in the largest free block: rounding slack, plus free space that's cut
+/// off from the largest block. The same `seed` always gives the same result.
+#[cfg(feature = "simulate")]
+pub fn simulate_fragmentation(
+    size: usize,
+    min_block_size: usize,
+    max_block_size: usize,
+    sizes: &[usize],
+    iterations: usize,
+    seed: u64,
+) -> f64 {
+    if sizes.is_empty() || iterations == 0 {
+        return 0.0;
+    }
+
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let mut bookkeeping = BuddyBookkeeping::new(size, min_block_size, max_block_size);
+    let mut live = Vec::new();
+    let mut total = 0.0;
+
+    for _ in 0..iterations {
+        if live.is_empty() || rng.bool() {
+            live.extend(bookkeeping.alloc(sizes[rng.usize(..sizes.len())]));
+        } else {
+            live.swap_remove(rng.usize(..live.len()));
+        }
+
+        bookkeeping.tidy();
+        total += fragmentation(&bookkeeping);
+    }
+
+    total / iterations as f64
+}
+
+/// The fraction of a freshly tidied arena that's neither in use nor in the largest free block.
+/// That block can be bigger than `max_block_size`, so this doesn't use `max_coalescible_free`.
+#[cfg(feature = "simulate")]
+fn fragmentation(bookkeeping: &BuddyBookkeeping) -> f64 {
+    let size = bookkeeping.blocks[bookkeeping.root].range.len();
+
+    let largest_free = bookkeeping
+        .leaves()
+        .filter(|block| matches!(block.state, BlockState::Available))
+        .map(|block| block.range.len())
+        .max()
+        .unwrap_or(0);
+
+    let free = size - bookkeeping.used_bytes();
+    let wasted = bookkeeping.internal_fragmentation() + free - largest_free;
+
+    wasted as f64 / size as f64
+}
+
 pub(crate) fn dealloc(arena: &mut Arena<Block>, block_index: Index) {
     arena[block_index].state = BlockState::Available;
 }
 
+/// Merges a split block back together if both of its children are available, removing the
+/// children. The inverse of `split`; unlike `tidy` it only looks at this one level. Returns
+/// whether it merged.
+pub fn merge(arena: &mut Arena<Block>, block_index: Index) -> bool {
+    let BlockState::Split(first, second) = arena[block_index].state else {
+        return false;
+    };
+
+    match (&arena[first].state, &arena[second].state) {
+        (BlockState::Available, BlockState::Available) => {
+            arena.remove(first).unwrap();
+            arena.remove(second).unwrap();
+
+            arena[block_index].state = BlockState::Available;
+
+            true
+        }
+        _ => false,
+    }
+}
+
 #[repr(transparent)]
 pub struct IsAvailable(bool);
 
+impl IsAvailable {
+    pub fn is_available(&self) -> bool {
+        self.0
+    }
+}
+
+impl From<IsAvailable> for bool {
+    fn from(value: IsAvailable) -> bool {
+        value.0
+    }
+}
+
 pub fn tidy(arena: &mut Arena<Block>, block_index: Index) -> IsAvailable {
     // go through and merge
     let block = &arena[block_index];
@@ -106,6 +333,22 @@ pub fn tidy(arena: &mut Arena<Block>, block_index: Index) -> IsAvailable {
 }
 
 pub fn tidy_gas(arena: &mut Arena<Block>, block_index: Index, gas: &mut usize) -> IsAvailable {
+    tidy_gas_biased(arena, block_index, gas, Bias::Left)
+}
+
+/// Which side of the tree to coalesce first when the budget might run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bias {
+    Left,
+    Right,
+}
+
+pub fn tidy_gas_biased(
+    arena: &mut Arena<Block>,
+    block_index: Index,
+    gas: &mut usize,
+    bias: Bias,
+) -> IsAvailable {
     if *gas == 0 {
         return IsAvailable(false);
     }
@@ -117,8 +360,20 @@ pub fn tidy_gas(arena: &mut Arena<Block>, block_index: Index, gas: &mut usize) -
 
     match block.state {
         BlockState::Split(first, second) => {
-            let first_available = tidy_gas(arena, first, gas).0;
-            let second_available = tidy_gas(arena, second, gas).0;
+            let (first_available, second_available) = match bias {
+                Bias::Left => {
+                    let first_available = tidy_gas_biased(arena, first, gas, bias).0;
+                    let second_available = tidy_gas_biased(arena, second, gas, bias).0;
+
+                    (first_available, second_available)
+                }
+                Bias::Right => {
+                    let second_available = tidy_gas_biased(arena, second, gas, bias).0;
+                    let first_available = tidy_gas_biased(arena, first, gas, bias).0;
+
+                    (first_available, second_available)
+                }
+            };
 
             if first_available && second_available {
                 arena.remove(first).unwrap();
@@ -136,6 +391,46 @@ pub fn tidy_gas(arena: &mut Arena<Block>, block_index: Index, gas: &mut usize) -
     }
 }
 
+/// Like `tidy`, but only merges blocks back together when the result would be at least
+/// `min_merge` big. Smaller free buddies are left split. Returns whether the whole block is
+/// free, even if it hasn't been merged.
+pub fn tidy_min_size(
+    arena: &mut Arena<Block>,
+    block_index: Index,
+    min_merge: usize,
+) -> IsAvailable {
+    let block = &arena[block_index];
+
+    match block.state {
+        BlockState::Split(first, second) => {
+            let first_free = tidy_min_size(arena, first, min_merge).0;
+            let second_free = tidy_min_size(arena, second, min_merge).0;
+
+            if first_free && second_free && arena[block_index].range.len() >= min_merge {
+                remove_descendants(arena, first);
+                remove_descendants(arena, second);
+                arena.remove(first).unwrap();
+                arena.remove(second).unwrap();
+
+                arena[block_index].state = BlockState::Available;
+            }
+
+            IsAvailable(first_free && second_free)
+        }
+        BlockState::Available => IsAvailable(true),
+        BlockState::Occupied => IsAvailable(false),
+    }
+}
+
+fn remove_descendants(arena: &mut Arena<Block>, block_index: Index) {
+    if let BlockState::Split(first, second) = arena[block_index].state {
+        remove_descendants(arena, first);
+        remove_descendants(arena, second);
+        arena.remove(first).unwrap();
+        arena.remove(second).unwrap();
+    }
+}
+
 pub fn tidy_timed(arena: &mut Arena<Block>, block_index: Index, deadline: Instant) -> IsAvailable {
     if Instant::now() >= deadline {
         // return not available so the recursion chain stops
@@ -165,3 +460,165 @@ pub fn tidy_timed(arena: &mut Arena<Block>, block_index: Index, deadline: Instan
         BlockState::Occupied => IsAvailable(false),
     }
 }
+
+#[test]
+fn test_tidy_availability() {
+    let mut arena = Arena::new();
+    let root = arena.insert(Block::available(0..64));
+
+    let index = alloc(&mut arena, root, 8).unwrap();
+    assert!(!tidy(&mut arena, root).is_available());
+
+    dealloc(&mut arena, index);
+    assert!(bool::from(tidy(&mut arena, root)));
+    assert_eq!(arena.len(), 1);
+
+    assert!(tidy_gas(&mut arena, root, &mut 1).is_available());
+    assert!(!tidy_gas(&mut arena, root, &mut 0).is_available());
+}
+
+#[test]
+fn test_pow2() {
+    assert_eq!(Pow2::new(0), None);
+    assert_eq!(Pow2::new(1).map(Pow2::get), Some(1));
+    assert_eq!(Pow2::new(48), None);
+    assert_eq!(Pow2::new(64).map(Pow2::get), Some(64));
+    assert_eq!(Pow2::new(usize::MAX), None);
+
+    const SIZE: Pow2 = Pow2::new_const(2048);
+    assert_eq!(SIZE.get(), 2048);
+}
+
+#[test]
+fn test_split() {
+    let mut arena = Arena::new();
+    let root = arena.insert(Block::available(0..64));
+
+    let (first, second) = split(&mut arena, root).unwrap();
+    assert!(matches!(arena[root].state, BlockState::Split(a, b) if (a, b) == (first, second)));
+    assert_eq!(
+        (arena[first].range.clone(), arena[second].range.clone()),
+        (0..32, 32..64)
+    );
+    assert!(matches!(arena[first].state, BlockState::Available));
+    assert!(matches!(arena[second].state, BlockState::Available));
+
+    // already split
+    assert!(split(&mut arena, root).is_none());
+
+    let single = arena.insert(Block::available(0..1));
+    assert!(split(&mut arena, single).is_none());
+}
+
+#[test]
+fn test_merge() {
+    let mut arena = Arena::new();
+    let root = arena.insert(Block::available(0..64));
+
+    let (first, _) = split(&mut arena, root).unwrap();
+    assert!(merge(&mut arena, root));
+    assert!(matches!(arena[root].state, BlockState::Available));
+    assert_eq!(arena.len(), 1);
+
+    // nothing left to merge
+    assert!(!merge(&mut arena, root));
+
+    let (first_again, _) = split(&mut arena, root).unwrap();
+    assert!(arena.get(first).is_none());
+    arena[first_again].state = BlockState::Occupied;
+    assert!(!merge(&mut arena, root));
+    assert_eq!(arena.len(), 3);
+}
+
+#[test]
+fn test_suggest_min_block_size() {
+    let mut sizes = vec![64; 18];
+    sizes.extend([8, 500, 60, 33]);
+    assert_eq!(suggest_min_block_size(&sizes, 4096), 64);
+
+    assert_eq!(suggest_min_block_size(&[3, 5, 7, 9], 4096), 8);
+    assert_eq!(suggest_min_block_size(&[10_000], 4096), 4096);
+    assert_eq!(suggest_min_block_size(&[], 4096), 1);
+}
+
+#[test]
+fn test_alloc_near_max() {
+    let base = usize::MAX / 2 + 1;
+
+    let mut arena = Arena::new();
+    let root = arena.insert(Block::available(base..(base + 64)));
+
+    let first = alloc(&mut arena, root, 8).unwrap();
+    let second = alloc(&mut arena, root, 32).unwrap();
+    assert_eq!(arena[first].range, base..(base + 8));
+    assert_eq!(arena[second].range, (base + 32)..(base + 64));
+
+    // the highest aligned 64 block whose end still fits in a usize
+    let start = usize::MAX - 127;
+    let top = arena.insert(Block::available(start..(start + 64)));
+    let (low, high) = split(&mut arena, top).unwrap();
+    assert_eq!(arena[low].range, start..(start + 32));
+    assert_eq!(arena[high].range, (start + 32)..(start + 64));
+
+    let piece = alloc(&mut arena, top, 8).unwrap();
+    assert_eq!(arena[piece].range, start..(start + 8));
+}
+
+#[test]
+fn test_min_arena_size() {
+    // 1625 elements, which would fit in 2048, but they take 3072 once rounded to blocks
+    let sizes = [1025, 600];
+    assert!(!BuddyBookkeeping::new(2048, 8, 2048).plan(&sizes));
+    assert_eq!(min_arena_size(&sizes, 8, 2048), Some(4096));
+
+    // 1800 elements, but three 1024 blocks don't go into two halves
+    assert_eq!(min_arena_size(&[600, 600, 600], 8, 1024), Some(4096));
+
+    // blocks that add up to the arena pack into it exactly, whatever the order
+    for sizes in [
+        [256, 1024, 256, 512],
+        [256, 512, 256, 1024],
+        [1024, 256, 512, 256],
+    ] {
+        assert_eq!(min_arena_size(&sizes, 8, 1024), Some(2048));
+    }
+
+    assert_eq!(min_arena_size(&[8; 16], 8, 64), Some(128));
+    assert_eq!(min_arena_size(&[], 8, 64), Some(64));
+    assert_eq!(min_arena_size(&[65], 8, 64), None);
+}
+
+#[cfg(feature = "simulate")]
+#[test]
+fn test_simulate_fragmentation() {
+    let uniform = simulate_fragmentation(4096, 8, 1024, &[64], 2000, 1);
+    let varied = simulate_fragmentation(4096, 8, 1024, &[9, 33, 100, 250, 700], 2000, 1);
+    assert!(uniform < varied, "{uniform} vs {varied}");
+
+    assert_eq!(
+        simulate_fragmentation(4096, 8, 1024, &[9, 33, 100], 500, 7),
+        simulate_fragmentation(4096, 8, 1024, &[9, 33, 100], 500, 7)
+    );
+    assert_eq!(simulate_fragmentation(4096, 8, 1024, &[], 500, 7), 0.0);
+
+    // nothing ever fits, so the arena stays empty
+    assert_eq!(simulate_fragmentation(4096, 8, 1024, &[2048], 500, 7), 0.0);
+}
+
+#[cfg(feature = "simulate")]
+#[test]
+fn test_fragmentation_when_free() {
+    let mut bookkeeping = BuddyBookkeeping::new(4096, 8, 1024);
+    assert_eq!(fragmentation(&bookkeeping), 0.0);
+
+    let allocations: Vec<_> = [9, 33, 100, 700]
+        .iter()
+        .map(|&count| bookkeeping.alloc(count).unwrap())
+        .collect();
+    bookkeeping.tidy();
+    assert!(fragmentation(&bookkeeping) > 0.0);
+
+    drop(allocations);
+    bookkeeping.tidy();
+    assert_eq!(fragmentation(&bookkeeping), 0.0);
+}
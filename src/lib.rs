@@ -1,8 +1,12 @@
 pub mod arena;
 pub mod buddy;
+pub mod rc;
+pub mod sharded;
+pub mod sub;
+pub mod writer;
 
 pub mod pretty_print {
-    use std::ops::Range;
+    use std::{fmt, ops::Range};
 
     use generational_arena::{Arena, Index};
 
@@ -41,4 +45,204 @@ pub mod pretty_print {
 
         build(&arena.blocks, arena.root)
     }
+
+    impl PrettyBlock {
+        fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+            let label = match self.state {
+                PrettyState::Split(..) => "split",
+                PrettyState::Available => "available",
+                PrettyState::Occupied => "occupied",
+            };
+
+            writeln!(
+                f,
+                "{:indent$}{}..{} {}",
+                "",
+                self.range.start,
+                self.range.end,
+                label,
+                indent = depth * 2
+            )?;
+
+            if let PrettyState::Split(first, second) = &self.state {
+                first.fmt_indented(f, depth + 1)?;
+                second.fmt_indented(f, depth + 1)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    impl fmt::Display for PrettyBlock {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.fmt_indented(f, 0)
+        }
+    }
+
+    /// One leaf block from `decode_layout`, in address order
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Segment {
+        pub len: usize,
+        pub occupied: bool,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DecodeError {
+        /// The input ended partway through
+        Truncated,
+        /// A number didn't fit in a `usize`
+        Overflow,
+        /// There were bytes left over after the last segment
+        TrailingBytes,
+    }
+
+    /// Encodes the leaf blocks compactly, for sending to a visualizer: the number of leaves,
+    /// then for each leaf, `log2` of its length (leaves are always powers of two) shifted left
+    /// by one with the low bit set if it's occupied, all as LEB128 varints
+    pub fn encode_layout(arena: &BuddyBookkeeping) -> Vec<u8> {
+        fn write_varint(bytes: &mut Vec<u8>, mut x: u64) {
+            while x >= 0x80 {
+                bytes.push((x as u8 & 0x7f) | 0x80);
+                x >>= 7;
+            }
+
+            bytes.push(x as u8);
+        }
+
+        let leaves: Vec<_> = arena.layout(false).collect();
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, leaves.len() as u64);
+        for leaf in leaves {
+            let class = u64::from(leaf.range.len().ilog2());
+            write_varint(&mut bytes, class << 1 | u64::from(leaf.occupied));
+        }
+
+        bytes
+    }
+
+    pub fn decode_layout(bytes: &[u8]) -> Result<Vec<Segment>, DecodeError> {
+        fn read_varint(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+            let mut x: u64 = 0;
+
+            for shift in (0..64).step_by(7) {
+                let (&byte, rest) = bytes.split_first().ok_or(DecodeError::Truncated)?;
+                *bytes = rest;
+
+                let bits = u64::from(byte & 0x7f);
+                if bits << shift >> shift != bits {
+                    return Err(DecodeError::Overflow);
+                }
+                x |= bits << shift;
+
+                if byte & 0x80 == 0 {
+                    return Ok(x);
+                }
+            }
+
+            Err(DecodeError::Overflow)
+        }
+
+        let mut bytes = bytes;
+        let to_usize = |x: u64| usize::try_from(x).map_err(|_| DecodeError::Overflow);
+
+        let count = to_usize(read_varint(&mut bytes)?)?;
+        // every segment takes at least a byte, so don't trust a count that's any bigger
+        let mut segments = Vec::with_capacity(count.min(bytes.len()));
+
+        for _ in 0..count {
+            let x = read_varint(&mut bytes)?;
+            let class = u32::try_from(x >> 1).map_err(|_| DecodeError::Overflow)?;
+
+            segments.push(Segment {
+                len: 1usize.checked_shl(class).ok_or(DecodeError::Overflow)?,
+                occupied: x & 1 == 1,
+            });
+        }
+
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        Ok(segments)
+    }
+
+    #[test]
+    fn test_encode_layout() {
+        let mut arena = BuddyBookkeeping::new(1 << 20, 16, 1 << 20);
+        let _a = arena.alloc(16).unwrap();
+        let _b = arena.alloc(1 << 18).unwrap();
+
+        let bytes = encode_layout(&arena);
+        let segments = decode_layout(&bytes).unwrap();
+
+        let expected: Vec<Segment> = arena
+            .layout(false)
+            .map(|leaf| Segment {
+                len: leaf.range.len(),
+                occupied: leaf.occupied,
+            })
+            .collect();
+        assert_eq!(segments, expected);
+        assert!(bytes.len() < segments.len() * 4);
+
+        let empty = BuddyBookkeeping::new(64, 16, 64);
+        assert_eq!(encode_layout(&empty), vec![1, 12]);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_encode_layout_huge() {
+        let mut arena = BuddyBookkeeping::new(1 << 63, 1 << 62, 1 << 63);
+        let _a = arena.alloc(1 << 62).unwrap();
+
+        let segments = decode_layout(&encode_layout(&arena)).unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    len: 1 << 62,
+                    occupied: true
+                },
+                Segment {
+                    len: 1 << 62,
+                    occupied: false
+                },
+            ]
+        );
+
+        let whole = BuddyBookkeeping::new(1 << 63, 1 << 63, 1 << 63);
+        let segments = decode_layout(&encode_layout(&whole)).unwrap();
+        assert_eq!(segments[0].len, 1 << 63);
+    }
+
+    #[test]
+    fn test_decode_layout_errors() {
+        let mut arena = BuddyBookkeeping::new(1 << 20, 16, 1 << 20);
+        let _a = arena.alloc(16).unwrap();
+        let bytes = encode_layout(&arena);
+
+        for len in 0..bytes.len() {
+            assert_eq!(decode_layout(&bytes[..len]), Err(DecodeError::Truncated));
+        }
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(decode_layout(&extra), Err(DecodeError::TrailingBytes));
+
+        assert_eq!(decode_layout(&[0xff; 11]), Err(DecodeError::Overflow));
+        // a length of 2^64
+        assert_eq!(decode_layout(&[1, 128, 1]), Err(DecodeError::Overflow));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut arena = BuddyBookkeeping::new(64, 16, 64);
+        let _a = arena.alloc(16).unwrap();
+
+        assert_eq!(
+            prettify(&arena).to_string(),
+            "0..64 split\n  0..32 split\n    0..16 occupied\n    16..32 available\n  32..64 available\n"
+        );
+    }
 }
@@ -0,0 +1,112 @@
+//! Arenas shared through an `Rc`, so allocations can be used without the arena at hand.
+
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    rc::Rc,
+};
+
+use crate::arena::{Allocation, BuddyArena};
+
+/// A `BuddyArena` behind an `Rc<RefCell<_>>`, handing out allocations that keep a reference
+/// to it. Cloning it shares the same arena.
+pub struct RcBuddyArena<T>(Rc<RefCell<BuddyArena<T>>>);
+
+impl<T> Clone for RcBuddyArena<T> {
+    fn clone(&self) -> RcBuddyArena<T> {
+        RcBuddyArena(self.0.clone())
+    }
+}
+
+/// An allocation bundled with the arena it came from. Dropping it frees the allocation on
+/// the arena's next tidy, like any other.
+pub struct OwnedView<T> {
+    arena: Rc<RefCell<BuddyArena<T>>>,
+    allocation: Allocation,
+}
+
+impl<T> RcBuddyArena<T> {
+    pub fn new(size: usize, min_block_size: usize, max_block_size: usize) -> RcBuddyArena<T>
+    where
+        T: Default,
+    {
+        RcBuddyArena::from(BuddyArena::new(size, min_block_size, max_block_size))
+    }
+
+    pub fn alloc(&self, count: usize) -> Option<OwnedView<T>> {
+        let allocation = self.0.borrow_mut().alloc(count)?;
+
+        Some(OwnedView {
+            arena: self.0.clone(),
+            allocation,
+        })
+    }
+
+    pub fn tidy(&self) {
+        self.0.borrow_mut().tidy();
+    }
+
+    /// Borrows the whole arena, panicking if a view is being written to
+    pub fn borrow(&self) -> Ref<'_, BuddyArena<T>> {
+        self.0.borrow()
+    }
+}
+
+impl<T> From<BuddyArena<T>> for RcBuddyArena<T> {
+    fn from(arena: BuddyArena<T>) -> RcBuddyArena<T> {
+        RcBuddyArena(Rc::new(RefCell::new(arena)))
+    }
+}
+
+impl<T> OwnedView<T> {
+    pub fn allocation(&self) -> &Allocation {
+        &self.allocation
+    }
+
+    /// Panics if any view into the same arena is being written to
+    pub fn read(&self) -> Ref<'_, [T]> {
+        Ref::map(self.arena.borrow(), |arena| arena.view(&self.allocation))
+    }
+
+    /// Panics if any other view into the same arena is being read or written
+    pub fn write(&self) -> RefMut<'_, [T]> {
+        RefMut::map(self.arena.borrow_mut(), |arena| {
+            arena.view_mut(&self.allocation)
+        })
+    }
+}
+
+#[test]
+fn test_owned_view() {
+    let arena: RcBuddyArena<u32> = RcBuddyArena::new(256, 8, 256);
+
+    let views: Vec<OwnedView<u32>> = (0..4)
+        .map(|i| {
+            let view = arena.alloc(32).unwrap();
+            view.write().fill(i);
+
+            view
+        })
+        .collect();
+    drop(arena);
+
+    for (i, view) in views.iter().enumerate() {
+        assert_eq!(view.read().len(), 32);
+        assert!(view.read().iter().all(|&x| x == i as u32));
+    }
+
+    // reads can overlap
+    let (first, second) = (views[0].read(), views[1].read());
+    assert_eq!(first[0] + second[0], 1);
+}
+
+#[test]
+fn test_owned_view_drop() {
+    let arena: RcBuddyArena<u8> = RcBuddyArena::new(64, 8, 64);
+
+    let view = arena.alloc(64).unwrap();
+    assert!(arena.alloc(8).is_none());
+
+    drop(view);
+    arena.tidy();
+    assert_eq!(arena.borrow().bookkeeping().used_bytes(), 0);
+}
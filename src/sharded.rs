@@ -0,0 +1,162 @@
+//! Splitting one arena into independently locked shards, for allocating from many threads.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use crate::arena::{Allocation, BuddyArena};
+
+/// `shards` arenas side by side, each behind its own lock. Allocations are handed out to the
+/// shards in turn, moving on to the next one if a shard is full, so threads rarely contend on
+/// the same lock. The cost is that a free block in one shard can't be merged with its
+/// neighbour in another.
+pub struct ShardedBuddyArena<T> {
+    shards: Vec<Mutex<BuddyArena<T>>>,
+    shard_size: usize,
+    next_shard: AtomicUsize,
+}
+
+/// An allocation from a `ShardedBuddyArena`, which remembers the shard it came from
+#[derive(Debug)]
+pub struct ShardedAllocation {
+    shard: usize,
+    offset: usize,
+    inner: Allocation,
+}
+
+impl ShardedAllocation {
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// The range across the whole sharded arena
+    pub fn range(&self) -> Range<usize> {
+        let range = self.inner.range();
+
+        (self.offset + range.start)..(self.offset + range.end)
+    }
+}
+
+impl<T> ShardedBuddyArena<T> {
+    /// `size` is split evenly between `shards`, and both must be powers of two
+    pub fn new(
+        size: usize,
+        shards: usize,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> ShardedBuddyArena<T>
+    where
+        T: Default,
+    {
+        assert!(shards.is_power_of_two() && shards <= size);
+        let shard_size = size / shards;
+
+        ShardedBuddyArena {
+            shards: (0..shards)
+                .map(|_| Mutex::new(BuddyArena::new(shard_size, min_block_size, max_block_size)))
+                .collect(),
+            shard_size,
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn lock(&self, shard: usize) -> std::sync::MutexGuard<'_, BuddyArena<T>> {
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn alloc(&self, count: usize) -> Option<ShardedAllocation> {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+
+        (0..self.shards.len()).find_map(|i| {
+            let shard = (start + i) % self.shards.len();
+            let inner = self.lock(shard).alloc(count)?;
+
+            Some(ShardedAllocation {
+                shard,
+                offset: shard * self.shard_size,
+                inner,
+            })
+        })
+    }
+
+    /// Runs `f` on the allocation's elements while its shard is locked
+    pub fn view<R>(&self, a: &ShardedAllocation, f: impl FnOnce(&[T]) -> R) -> R {
+        f(self.lock(a.shard).view(&a.inner))
+    }
+
+    pub fn view_mut<R>(&self, a: &ShardedAllocation, f: impl FnOnce(&mut [T]) -> R) -> R {
+        f(self.lock(a.shard).view_mut(&a.inner))
+    }
+
+    /// Tidies every shard, one at a time
+    pub fn tidy(&self) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).tidy();
+        }
+    }
+}
+
+#[test]
+fn test_sharded_buddy_arena() {
+    let arena: ShardedBuddyArena<u32> = ShardedBuddyArena::new(4096, 4, 8, 1024);
+
+    let allocations: Vec<ShardedAllocation> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let arena = &arena;
+
+                scope.spawn(move || {
+                    (0..16)
+                        .map(|i| {
+                            let a = arena.alloc(16).unwrap();
+                            arena.view_mut(&a, |view| view.fill(thread * 100 + i));
+
+                            a
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    });
+
+    let mut ranges: Vec<_> = allocations.iter().map(ShardedAllocation::range).collect();
+    ranges.sort_by_key(|range| range.start);
+    assert!(ranges.windows(2).all(|pair| pair[0].end <= pair[1].start));
+
+    for a in &allocations {
+        assert_eq!(a.range().start / 1024, a.shard());
+    }
+
+    // every shard got some of the work, and each view went to the right one
+    for shard in 0..arena.shard_count() {
+        assert!(allocations.iter().any(|a| a.shard() == shard));
+    }
+    let mut values: Vec<u32> = allocations
+        .iter()
+        .map(|a| arena.view(a, |view| view[0]))
+        .collect();
+    values.sort_unstable();
+    let expected: Vec<u32> = (0..4)
+        .flat_map(|thread| (0..16).map(move |i| thread * 100 + i))
+        .collect();
+    assert_eq!(values, expected);
+
+    drop(allocations);
+    arena.tidy();
+    assert!(arena.alloc(1024).is_some());
+}
@@ -0,0 +1,107 @@
+//! Nested allocation inside a single parent allocation.
+
+use std::ops::Range;
+
+use crate::arena::{Allocation, BuddyArena, BuddyBookkeeping};
+
+/// An independent buddy tree covering the range of one parent allocation, for sub-allocating
+/// pieces with different lifetimes. Ranges of sub-allocations are relative to the start of the
+/// parent, and are viewed through the arena the parent came from.
+///
+/// The sub-arena owns the parent, so the parent stays allocated until the sub-arena is
+/// dropped (or taken apart with `into_parent`), at which point any sub-allocations still
+/// around stop meaning anything.
+pub struct SubBuddy {
+    parent: Allocation,
+    bookkeeping: BuddyBookkeeping,
+}
+
+impl SubBuddy {
+    /// Uses the largest power of two that fits in the parent's range
+    pub fn new(parent: Allocation, min_block_size: usize, max_block_size: usize) -> SubBuddy {
+        let len = parent.range().len();
+        assert!(len > 0);
+
+        let size = 1 << len.ilog2();
+
+        SubBuddy {
+            parent,
+            bookkeeping: BuddyBookkeeping::new(size, min_block_size, max_block_size),
+        }
+    }
+
+    pub fn parent(&self) -> &Allocation {
+        &self.parent
+    }
+
+    pub fn into_parent(self) -> Allocation {
+        self.parent
+    }
+
+    pub fn bookkeeping(&self) -> &BuddyBookkeeping {
+        &self.bookkeeping
+    }
+
+    pub fn alloc(&mut self, count: usize) -> Option<Allocation> {
+        self.bookkeeping.alloc(count)
+    }
+
+    pub fn tidy(&mut self) {
+        self.bookkeeping.tidy();
+    }
+
+    /// Where a sub-allocation sits in the parent arena
+    pub fn absolute_range(&self, a: &Allocation) -> Range<usize> {
+        let start = self.parent.range().start;
+
+        (start + a.range().start)..(start + a.range().end)
+    }
+
+    pub fn view<'a, T>(&self, arena: &'a BuddyArena<T>, a: &Allocation) -> &'a [T] {
+        &arena.view(&self.parent)[a.range()]
+    }
+
+    pub fn view_mut<'a, T>(&self, arena: &'a mut BuddyArena<T>, a: &Allocation) -> &'a mut [T] {
+        &mut arena.view_mut(&self.parent)[a.range()]
+    }
+}
+
+#[test]
+fn test_sub_buddy() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(2048, 8, 256);
+
+    let _filler = arena.alloc(256).unwrap();
+    let parent = arena.alloc(256).unwrap();
+    let parent_range = parent.range();
+
+    let mut sub = SubBuddy::new(parent, 8, 64);
+    let pieces: Vec<_> = [8, 30, 64, 3]
+        .iter()
+        .map(|&n| sub.alloc(n).unwrap())
+        .collect();
+
+    for piece in &pieces {
+        let range = sub.absolute_range(piece);
+        assert!(parent_range.start <= range.start && range.end <= parent_range.end);
+    }
+
+    sub.view_mut(&mut arena, &pieces[1]).fill(7);
+    let absolute = sub.absolute_range(&pieces[1]);
+    assert!(
+        arena.view(sub.parent())[absolute.start - parent_range.start..][..30]
+            .iter()
+            .all(|&x| x == 7)
+    );
+    assert_eq!(sub.view(&arena, &pieces[1]).len(), 30);
+
+    drop(pieces);
+    sub.tidy();
+    assert_eq!(sub.bookkeeping().used_bytes(), 0);
+
+    // the parent is only freed once the sub-arena is done with it
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 512);
+    drop(sub);
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 256);
+}
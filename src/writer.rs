@@ -0,0 +1,165 @@
+//! Appending to an arena without knowing the final length up front.
+
+use crate::arena::{Allocation, BuddyArena};
+
+/// Pushes elements into an arena, allocating a new chunk whenever the last one fills up.
+/// Each chunk is twice as big as the one before, up to the biggest block size, falling back
+/// to smaller chunks when the arena is too full for the next size.
+pub struct BuddyWriter<'a, T> {
+    arena: &'a mut BuddyArena<T>,
+    chunks: Vec<Allocation>,
+    /// How much of the last chunk has been written
+    last_len: usize,
+    len: usize,
+}
+
+impl<'a, T> BuddyWriter<'a, T> {
+    pub fn new(arena: &'a mut BuddyArena<T>) -> BuddyWriter<'a, T> {
+        BuddyWriter {
+            arena,
+            chunks: Vec::new(),
+            last_len: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Hands `value` back if there's no room left for it
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let full = self
+            .chunks
+            .last()
+            .is_none_or(|chunk| self.last_len == chunk.range().len());
+
+        if full && !self.grow() {
+            return Err(value);
+        }
+
+        let chunk = self.chunks.last().unwrap();
+        self.arena.view_mut(chunk)[self.last_len] = value;
+
+        self.last_len += 1;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Stops at, and hands back, the first value there's no room for
+    pub fn extend(&mut self, values: impl IntoIterator<Item = T>) -> Result<(), T> {
+        for value in values {
+            self.push(value)?;
+        }
+
+        Ok(())
+    }
+
+    fn grow(&mut self) -> bool {
+        let mut classes = self.arena.bookkeeping().size_classes();
+        let min = classes.next().unwrap();
+        let max = classes.last().unwrap_or(min);
+
+        let mut size = self
+            .chunks
+            .last()
+            .map_or(min, |chunk| (chunk.range().len() * 2).min(max));
+
+        loop {
+            if let Some(chunk) = self.arena.alloc(size) {
+                self.chunks.push(chunk);
+                self.last_len = 0;
+
+                return true;
+            }
+
+            if size <= min {
+                return false;
+            }
+
+            size /= 2;
+        }
+    }
+
+    /// The chunks that were written, in order, with the last one trimmed down to what was
+    /// actually pushed
+    pub fn finish(self) -> Vec<Allocation> {
+        trim_last(self.chunks, self.last_len)
+    }
+
+    /// Moves everything into a single allocation, freeing the chunks. If there isn't a block
+    /// big enough, the chunks are handed back like `finish` instead.
+    pub fn finish_compacted(self) -> Result<Allocation, Vec<Allocation>> {
+        let arena = self.arena;
+        let chunks = trim_last(self.chunks, self.last_len);
+
+        let Some(dest) = arena.alloc(self.len) else {
+            return Err(chunks);
+        };
+
+        let mut offset = 0;
+        for chunk in &chunks {
+            let mut views = arena.views_mut(&[chunk, &dest]).unwrap();
+            let dest_view = views.pop().unwrap();
+            let chunk_view = views.pop().unwrap();
+
+            dest_view[offset..offset + chunk_view.len()].swap_with_slice(chunk_view);
+            offset += chunk_view.len();
+        }
+
+        Ok(dest)
+    }
+}
+
+fn trim_last(mut chunks: Vec<Allocation>, last_len: usize) -> Vec<Allocation> {
+    if let Some(last) = chunks.pop() {
+        // the unwritten tail shares the block, so dropping it doesn't free anything
+        let (written, _) = last.split_at(last_len);
+        chunks.push(written);
+    }
+
+    chunks
+}
+
+#[test]
+fn test_buddy_writer() {
+    let mut arena: BuddyArena<u32> = BuddyArena::new(4096, 8, 1024);
+
+    let mut writer = BuddyWriter::new(&mut arena);
+    writer.extend(0..1000).unwrap();
+    assert_eq!(writer.len(), 1000);
+
+    let chunks = writer.finish();
+    let read: Vec<u32> = chunks
+        .iter()
+        .flat_map(|chunk| arena.view(chunk).iter().copied())
+        .collect();
+    assert!(read.iter().copied().eq(0..1000));
+
+    drop(chunks);
+    arena.tidy();
+
+    let mut writer = BuddyWriter::new(&mut arena);
+    writer.extend(0..1000).unwrap();
+    let compacted = writer.finish_compacted().unwrap();
+    assert!(arena.view(&compacted).iter().copied().eq(0..1000));
+
+    // only the compacted allocation is left
+    arena.tidy();
+    assert_eq!(arena.bookkeeping().used_bytes(), 1024);
+}
+
+#[test]
+fn test_buddy_writer_full() {
+    let mut arena: BuddyArena<u8> = BuddyArena::new(32, 8, 32);
+    let _taken = arena.alloc(16).unwrap();
+
+    let mut writer = BuddyWriter::new(&mut arena);
+    assert_eq!(writer.extend(0..20), Err(16));
+    assert_eq!(writer.len(), 16);
+}